@@ -0,0 +1,32 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// C/C++から使うためのヘッダーをinclude/se_dylib.hに生成する
+// pkg-configのファイルは`pkg_config.sh`で生成する
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    let mut header = Vec::new();
+    cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Unable to generate se_dylib.h")
+        .write(&mut header);
+
+    // 内容が変わらない場合は書き込まず、C/C++側の再ビルドを避ける
+    write_if_changed(&crate_dir.join("include").join("se_dylib.h"), &header);
+}
+
+fn write_if_changed(path: &Path, contents: &[u8]) {
+    if fs::read(path).is_ok_and(|old| old == contents) {
+        return;
+    }
+
+    fs::write(path, contents).unwrap();
+}
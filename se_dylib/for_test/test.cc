@@ -1,53 +1,80 @@
+// ビルド: cargo build && ./pkg_config.sh target/debug
+//       g++ for_test/test.cc $(PKG_CONFIG_PATH=target/debug pkg-config --cflags --libs se_dylib) -lpthread
+
 #include <iostream>
+#include <string>
 #include <thread>
 #include <chrono>
 
-extern "C"
+#include "se_dylib.h"
+
+const unsigned short PORT = 10020;
+
+void print_error(const char *what)
 {
-    void *new_server();
-    int s_send(void *server, const char *data, int len);
-    int s_receive(void *server, char *data, int len);
-    int s_send_table(void *server, const double *data, int row_num, int col_num);
-    int s_receive_table(void *server, double *data, int len, int *row_num, int *col_num);
-    void s_close(void *server);
-
-    void *new_client(const char *server_address);
-    int c_send(void *client, const char *data, int len);
-    int c_receive(void *client, char *data, int len);
-    int c_send_table(void *client, const double *data, int row_num, int col_num);
-    int c_receive_table(void *client, double *data, int len, int *row_num, int *col_num);
-    void c_close(void *client);
+    std::cerr << what << " (code " << last_error_code() << "): " << last_error_message() << std::endl;
 }
 
 void server()
 {
-    void *server = new_server();
-    char data[1024];
-    int len = s_receive(server, data, 1024);
+    SeServer *server = new_server_at(nullptr, PORT);
+
+    if (server == nullptr)
+    {
+        print_error("Error creating server");
+        return;
+    }
+
+    // バッファが足りなければメッセージは保留されるので、長さを確認して受信し直す
+    unsigned char small[2];
+    if (s_receive(server, small, 2) >= 0)
+    {
+        std::cerr << "Error: message did not fit but was received" << std::endl;
+        return;
+    }
+
+    unsigned char data[1024] = {};
+    int len = s_peek_length(server);
 
     if (len < 0)
     {
-        std::cerr << "Error receiving data" << std::endl;
+        print_error("Error peeking data");
         return;
     }
 
-    std::cout << "Received: " << data << std::endl;
+    len = s_receive(server, data, len);
+
+    if (len < 0)
+    {
+        print_error("Error receiving data");
+        return;
+    }
+
+    std::cout << "Received: " << std::string(data, data + len) << std::endl;
 
     int res = s_send(server, data, len);
 
     if (res != 0)
     {
-        std::cerr << "Error sending data" << std::endl;
+        print_error("Error sending data");
+        return;
+    }
+
+    unsigned int row, col;
+    res = s_peek_table_shape(server, &row, &col);
+
+    if (res != 0)
+    {
+        print_error("Error peeking table");
         return;
     }
 
-    double *table = new double[6];
-    int row, col;
-    res = s_receive_table(server, table, 6, &row, &col);
+    double *table = new double[row * col];
+    res = s_receive_table(server, table, row * col, &row, &col);
 
     if (res != 0)
     {
-        std::cerr << "Error receiving table" << std::endl;
+        print_error("Error receiving table");
         return;
     }
 
@@ -55,59 +82,111 @@ void server()
 
     if (res != 0)
     {
-        std::cerr << "Error sending table" << std::endl;
+        print_error("Error sending table");
         return;
     }
 
+    delete[] table;
     s_close(server);
 }
 
+// retryが追加される前のヘッダーでビルドされたプログラムの設定
+struct OldOptions
+{
+    unsigned int size;
+    unsigned short port;
+    unsigned int read_timeout_ms;
+    unsigned int write_timeout_ms;
+    unsigned int connect_timeout_ms;
+};
+
 void client(const char *server_address)
 {
-    void *client = new_client(server_address);
-    int res = c_send(client, "Hello", 5);
+    // default_optionsで初期化していない設定は受け付けない
+    SeOptions uninitialized = {};
+    if (new_client_with_options(server_address, &uninitialized) != nullptr ||
+        last_error_code() != SE_ERROR_CODE_INVALID_ARGUMENT)
+    {
+        std::cerr << "Error: uninitialized options were accepted" << std::endl;
+        return;
+    }
+
+    // このライブラリより新しいヘッダーの設定も受け付けない
+    SeOptions newer = default_options();
+    newer.size += sizeof(int);
+    if (new_client_with_options(server_address, &newer) != nullptr ||
+        last_error_code() != SE_ERROR_CODE_INVALID_ARGUMENT)
+    {
+        std::cerr << "Error: options from a newer header were accepted" << std::endl;
+        return;
+    }
+
+    // 古いヘッダーの設定は、足りない項目を既定値にして受け付ける
+    // 誰も待ち受けていないポートを指定したので、接続できずに終わる
+    OldOptions old = {sizeof(OldOptions), PORT + 1, 0, 0, 1000};
+    if (new_client_with_options(server_address, reinterpret_cast<SeOptions *>(&old)) != nullptr ||
+        last_error_code() != SE_ERROR_CODE_CONNECTION_REFUSED)
+    {
+        print_error("Error: options from an older header were not accepted");
+        return;
+    }
+
+    SeOptions options = default_options();
+    options.port = PORT;
+    options.read_timeout_ms = 5000;
+    options.retry = 1;
+    SeClient *client = new_client_with_options(server_address, &options);
+
+    if (client == nullptr)
+    {
+        print_error("Error creating client");
+        return;
+    }
+
+    const unsigned char hello[] = "Hello";
+    int res = c_send(client, hello, 5);
 
     if (res != 0)
     {
-        std::cerr << "Error sending data" << std::endl;
+        print_error("Error sending data");
         return;
     }
 
-    char data[1024];
-    int len = c_receive(client, data, 1024);
+    unsigned int len;
+    unsigned char *data = c_receive_alloc(client, &len);
 
-    if (len < 0)
+    if (data == nullptr)
     {
-        std::cerr << "Error receiving data" << std::endl;
+        print_error("Error receiving data");
         return;
     }
 
-    std::cout << "Received: " << data << std::endl;
+    std::cout << "Received: " << std::string(data, data + len) << std::endl;
+    free_buffer(data, len);
 
     double table[6] = {1, 2, 3, 4, 5, 6};
-    int row = 2;
-    int col = 3;
+    unsigned int row = 2;
+    unsigned int col = 3;
     res = c_send_table(client, table, row, col);
 
     if (res != 0)
     {
-        std::cerr << "Error sending table" << std::endl;
+        print_error("Error sending table");
         return;
     }
 
-    double *table2 = new double[6];
-    int row2, col2;
-    res = c_receive_table(client, table2, 6, &row2, &col2);
+    unsigned int row2, col2;
+    double *table2 = c_receive_table_alloc(client, &row2, &col2);
 
-    if (res != 0)
+    if (table2 == nullptr)
     {
-        std::cerr << "Error receiving table" << std::endl;
+        print_error("Error receiving table");
         return;
     }
 
-    for (int i = 0; i < row2; i++)
+    for (unsigned int i = 0; i < row2; i++)
     {
-        for (int j = 0; j < col2; j++)
+        for (unsigned int j = 0; j < col2; j++)
         {
             if (table[i * col2 + j] != table2[i * col2 + j])
             {
@@ -119,6 +198,8 @@ void client(const char *server_address)
 
     std::cout << "Received valid table" << std::endl;
 
+    free_table(table2, row2, col2);
+
     c_close(client);
 }
 
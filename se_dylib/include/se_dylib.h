@@ -0,0 +1,280 @@
+#ifndef SE_DYLIB_H
+#define SE_DYLIB_H
+
+/* このファイルはbuild.rsで自動生成される。直接編集しないこと */
+
+typedef enum SeErrorCode {
+  SE_ERROR_CODE_OK = 0,
+  // 引数にNULLが渡された
+  SE_ERROR_CODE_NULL_POINTER = 1,
+  // 文字列がUTF-8でない、表の大きさが合わないなど
+  SE_ERROR_CODE_INVALID_ARGUMENT = 2,
+  SE_ERROR_CODE_CONNECTION_REFUSED = 3,
+  // 相手が切断した
+  SE_ERROR_CODE_CONNECTION_CLOSED = 4,
+  SE_ERROR_CODE_TIMEOUT = 5,
+  // 受信したデータが渡されたバッファに入らない
+  SE_ERROR_CODE_BUFFER_TOO_SMALL = 6,
+  // 受信したデータが壊れている(JSONとして読めないなど)
+  SE_ERROR_CODE_INVALID_DATA = 7,
+  // その他の入出力エラー
+  SE_ERROR_CODE_IO = 8,
+} SeErrorCode;
+
+// 中学側の通信路。`new_client`で作り、`c_close`で解放する
+typedef struct SeClient SeClient;
+
+// 予備校側の通信路。`new_server`で作り、`s_close`で解放する
+typedef struct SeServer SeServer;
+
+// 接続の設定。`default_options`で既定値を取得してから必要な項目だけ変更すること
+// 時間はミリ秒で、0なら無制限
+typedef struct SeOptions {
+  // 構造体のバイト数。`default_options`が設定するので変更しないこと
+  // 項目を増やしたときに、古いヘッダーでビルドされたプログラムを見分けるのに使う
+  unsigned int size;
+  // 予備校側が待ち受ける(中学側が接続する)ポート
+  unsigned short port;
+  unsigned int read_timeout_ms;
+  unsigned int write_timeout_ms;
+  // 中学側のみ
+  unsigned int connect_timeout_ms;
+  // 0以外なら、予備校側が起動するまで接続を再試行する(中学側のみ)
+  int retry;
+} SeOptions;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// 中学側からの接続を待つ。失敗した場合はNULLを返す
+struct SeServer *new_server(void);
+
+// addressとportで待ち受ける。addressがNULLならループバックアドレスを使う
+//
+// # Safety
+//
+// addressはNULLか、NUL終端の文字列であること
+struct SeServer *new_server_at(const char *address, unsigned short port);
+
+// optionsがNULLなら既定の設定を使う
+//
+// # Safety
+//
+// addressはNULLか、NUL終端の文字列であること
+// optionsはNULLか、`default_options`で初期化した`SeOptions`を指すこと
+struct SeServer *new_server_with_options(const char *address, const struct SeOptions *options);
+
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+// dataはNULLか、lenバイト読めること
+int s_send(struct SeServer *com,
+           const unsigned char *data,
+           unsigned int len);
+
+// 受信したバイト数を返す。バッファが足りない場合は-1を返し、メッセージは保留される
+//
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+// bufはNULLか、lenバイト書き込めること
+int s_receive(struct SeServer *com,
+              unsigned char *buf,
+              unsigned int len);
+
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+// tableはNULLか、row_num * col_num個読めること
+int s_send_table(struct SeServer *com,
+                 const double *table,
+                 unsigned int row_num,
+                 unsigned int col_num);
+
+// バッファが足りない場合は-1を返し、表は保留される
+//
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+// table_bufはNULLか、len個書き込めること。row_numとcol_numはNULLか書き込めること
+int s_receive_table(struct SeServer *com,
+                    double *table_buf,
+                    unsigned int len,
+                    unsigned int *row_num,
+                    unsigned int *col_num);
+
+// 次のメッセージのバイト数を返す。メッセージは次の受信関数で渡される
+//
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+int s_peek_length(struct SeServer *com);
+
+// ライブラリ側で確保したバッファで受け取る。`free_buffer`で解放すること
+//
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+// lenはNULLか書き込めること
+unsigned char *s_receive_alloc(struct SeServer *com,
+                               unsigned int *len);
+
+// 次の表の行数と列数を返す。表は次の受信関数で渡される
+//
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+// row_numとcol_numはNULLか書き込めること
+int s_peek_table_shape(struct SeServer *com,
+                       unsigned int *row_num,
+                       unsigned int *col_num);
+
+// ライブラリ側で確保したバッファで受け取る。`free_table`で解放すること
+//
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+// row_numとcol_numはNULLか書き込めること
+double *s_receive_table_alloc(struct SeServer *com,
+                              unsigned int *row_num,
+                              unsigned int *col_num);
+
+// # Safety
+//
+// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+// 閉じたハンドルは二度と使わないこと
+void s_close(struct SeServer *com);
+
+// 予備校側に接続する。失敗した場合はNULLを返す
+//
+// # Safety
+//
+// server_addressはNULLか、NUL終端の文字列であること
+struct SeClient *new_client(const char *server_address);
+
+// # Safety
+//
+// server_addressはNULLか、NUL終端の文字列であること
+struct SeClient *new_client_at(const char *server_address, unsigned short port);
+
+// optionsがNULLなら既定の設定を使う
+//
+// # Safety
+//
+// server_addressはNULLか、NUL終端の文字列であること
+// optionsはNULLか、`default_options`で初期化した`SeOptions`を指すこと
+struct SeClient *new_client_with_options(const char *server_address,
+                                         const struct SeOptions *options);
+
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+// dataはNULLか、lenバイト読めること
+int c_send(struct SeClient *com,
+           const unsigned char *data,
+           unsigned int len);
+
+// 受信したバイト数を返す。バッファが足りない場合は-1を返し、メッセージは保留される
+//
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+// bufはNULLか、lenバイト書き込めること
+int c_receive(struct SeClient *com,
+              unsigned char *buf,
+              unsigned int len);
+
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+// tableはNULLか、row_num * col_num個読めること
+int c_send_table(struct SeClient *com,
+                 const double *table,
+                 unsigned int row_num,
+                 unsigned int col_num);
+
+// バッファが足りない場合は-1を返し、表は保留される
+//
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+// table_bufはNULLか、len個書き込めること。row_numとcol_numはNULLか書き込めること
+int c_receive_table(struct SeClient *com,
+                    double *table_buf,
+                    unsigned int len,
+                    unsigned int *row_num,
+                    unsigned int *col_num);
+
+// 次のメッセージのバイト数を返す。メッセージは次の受信関数で渡される
+//
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+int c_peek_length(struct SeClient *com);
+
+// ライブラリ側で確保したバッファで受け取る。`free_buffer`で解放すること
+//
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+// lenはNULLか書き込めること
+unsigned char *c_receive_alloc(struct SeClient *com,
+                               unsigned int *len);
+
+// 次の表の行数と列数を返す。表は次の受信関数で渡される
+//
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+// row_numとcol_numはNULLか書き込めること
+int c_peek_table_shape(struct SeClient *com,
+                       unsigned int *row_num,
+                       unsigned int *col_num);
+
+// ライブラリ側で確保したバッファで受け取る。`free_table`で解放すること
+//
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+// row_numとcol_numはNULLか書き込めること
+double *c_receive_table_alloc(struct SeClient *com,
+                              unsigned int *row_num,
+                              unsigned int *col_num);
+
+// # Safety
+//
+// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+// 閉じたハンドルは二度と使わないこと
+void c_close(struct SeClient *com);
+
+// `*_receive_alloc`で受け取ったバッファを解放する。lenは受け取ったバイト数
+//
+// # Safety
+//
+// bufはNULLか、`*_receive_alloc`が返してまだ解放していないバッファであること。lenはそのとき受け取った長さ
+void free_buffer(unsigned char *buf,
+                 unsigned int len);
+
+// `*_receive_table_alloc`で受け取った表を解放する
+//
+// # Safety
+//
+// tableはNULLか、`*_receive_table_alloc`が返してまだ解放していない表であること。row_numとcol_numはそのとき受け取った大きさ
+void free_table(double *table,
+                unsigned int row_num,
+                unsigned int col_num);
+
+// 呼び出したスレッドで最後に呼んだ関数の結果
+enum SeErrorCode last_error_code(void);
+
+// 返したポインタは同じスレッドで次に関数を呼ぶまで有効。エラーがなければNULL
+const char *last_error_message(void);
+
+struct SeOptions default_options(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SE_DYLIB_H */
@@ -0,0 +1,25 @@
+#!/bin/sh
+# se_dylib.pcをライブラリと同じディレクトリに生成する
+#
+# 使い方: ./pkg_config.sh [ライブラリのディレクトリ]
+#   ディレクトリを省略するとtarget/releaseに生成する
+#
+# 例:
+#   cargo build --release && ./pkg_config.sh
+#   g++ for_test/test.cc $(PKG_CONFIG_PATH=target/release pkg-config --cflags --libs se_dylib) -lpthread
+set -eu
+
+crate_dir=$(cd "$(dirname "$0")" && pwd)
+lib_dir=$(cd "${1:-$crate_dir/target/release}" && pwd)
+version=$(sed -n 's/^version = "\(.*\)"$/\1/p' "$crate_dir/Cargo.toml" | head -n 1)
+
+cat > "$lib_dir/se_dylib.pc" <<PC
+includedir=$crate_dir/include
+libdir=$lib_dir
+
+Name: se_dylib
+Description: TCP communication library for the content security experiment
+Version: $version
+Libs: -L\${libdir} -lse_dylib
+Cflags: -I\${includedir}
+PC
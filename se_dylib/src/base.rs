@@ -1,41 +1,140 @@
+use crate::error::{self, ErrorCode};
+use anyhow::Result;
 use se_rust::comm::Communicator;
-use std::ffi::{c_double, c_int, c_uchar, c_uint, c_void};
-use std::mem::forget;
+use se_rust::matrix::Matrix;
+use std::ffi::{c_double, c_int, c_uchar, c_uint};
+use std::ptr::slice_from_raw_parts_mut;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 
+// 受信したがバッファが足りずに渡せなかったメッセージ
+// 次の受信関数の呼び出しで渡す
+enum Pending {
+    Message(Vec<u8>),
+    Table(Matrix),
+}
+
+// C側に渡すハンドルの中身
+pub(crate) struct Handle<C> {
+    com: C,
+    pending: Option<Pending>,
+}
+
+impl<C: Communicator> Handle<C> {
+    pub(crate) fn new(com: C) -> Self {
+        Self { com, pending: None }
+    }
+
+    // 保留中のメッセージがなければ受信する
+    fn peek_message(&mut self) -> Result<&[u8]> {
+        if self.pending.is_none() {
+            self.pending = Some(Pending::Message(self.com.receive()?));
+        }
+
+        match &self.pending {
+            Some(Pending::Message(data)) => Ok(data),
+            _ => Err(error::fail(
+                ErrorCode::InvalidArgument,
+                "A table is pending; receive it as a table first",
+            )),
+        }
+    }
+
+    fn peek_table(&mut self) -> Result<&Matrix> {
+        if self.pending.is_none() {
+            self.pending = Some(Pending::Table(self.com.receive_matrix()?));
+        }
+
+        match &self.pending {
+            Some(Pending::Table(table)) => Ok(table),
+            _ => Err(error::fail(
+                ErrorCode::InvalidArgument,
+                "A message is pending; receive it as a message first",
+            )),
+        }
+    }
+
+    fn take_message(&mut self) -> Result<Vec<u8>> {
+        self.peek_message()?;
+        match self.pending.take() {
+            Some(Pending::Message(data)) => Ok(data),
+            _ => unreachable!(),
+        }
+    }
+
+    fn take_table(&mut self) -> Result<Matrix> {
+        self.peek_table()?;
+        match self.pending.take() {
+            Some(Pending::Table(table)) => Ok(table),
+            _ => unreachable!(),
+        }
+    }
+}
+
+// ハンドルは呼び出し側が持ち続けるので、ここでは借りるだけにする
+fn handle<C>(com: Option<&mut Handle<C>>) -> Result<&mut Handle<C>> {
+    com.ok_or_else(|| error::fail(ErrorCode::NullPointer, "Null handle was passed"))
+}
+
+pub(crate) fn check_null<T>(ptr: *const T) -> Result<()> {
+    if ptr.is_null() {
+        return Err(error::fail(
+            ErrorCode::NullPointer,
+            "Null pointer was passed",
+        ));
+    }
+
+    Ok(())
+}
+
 pub(crate) unsafe fn send<C: Communicator>(
-    com: *mut c_void,
+    com: Option<&mut Handle<C>>,
     data: *const c_uchar,
     len: c_uint,
-) -> c_int {
-    let mut com = Box::from_raw(com as *mut C);
+) -> Result<c_int> {
+    let com = handle(com)?;
+    check_null(data)?;
     let data = from_raw_parts(data, len as usize);
 
-    let Ok(_) = com.send(data) else {
-        return -1;
-    };
+    com.com.send(data)?;
 
     // println!("sended: {:?}", data);
 
-    forget(com);
+    Ok(0)
+}
 
-    0
+// 長さを戻り値で返すので、c_intに収まらない場合はエラーにする
+// メッセージは保留されるので、`*_receive_alloc`で受け取れる
+fn length_as_c_int(len: usize) -> Result<c_int> {
+    c_int::try_from(len).map_err(|_| {
+        error::fail(
+            ErrorCode::InvalidData,
+            format!("Received {} bytes, which is too long to return as int", len),
+        )
+    })
 }
 
+// バッファが足りない場合、メッセージは保留されるので`peek_length`で長さを確認して受信し直せる
 pub(crate) unsafe fn receive<C: Communicator>(
-    com: *mut c_void,
+    com: Option<&mut Handle<C>>,
     buf: *mut c_uchar,
     len: c_uint,
-) -> c_int {
-    let mut com = Box::from_raw(com as *mut C);
-    let Ok(data) = com.receive() else {
-        return -1;
-    };
-
-    if len < data.len() as u32 {
-        return -1;
+) -> Result<c_int> {
+    let com = handle(com)?;
+    check_null(buf)?;
+    let data_len = com.peek_message()?.len();
+    let result = length_as_c_int(data_len)?;
+
+    if (len as usize) < data_len {
+        return Err(error::fail(
+            ErrorCode::BufferTooSmall,
+            format!(
+                "Received {} bytes but the buffer has only {} bytes",
+                data_len, len
+            ),
+        ));
     }
 
+    let data = com.take_message()?;
     let buf = from_raw_parts_mut(buf, len as usize);
 
     for (i, b) in data.iter().enumerate() {
@@ -44,74 +143,144 @@ pub(crate) unsafe fn receive<C: Communicator>(
 
     // println!("received: {:?}", data);
 
-    forget(com);
+    Ok(result)
+}
+
+// 次のメッセージのバイト数。メッセージは保留され、次の受信関数で渡される
+pub(crate) fn peek_length<C: Communicator>(com: Option<&mut Handle<C>>) -> Result<c_int> {
+    let com = handle(com)?;
+
+    length_as_c_int(com.peek_message()?.len())
+}
+
+// ライブラリ側で確保したバッファで受け取る。`free_buffer`で解放すること
+pub(crate) unsafe fn receive_alloc<C: Communicator>(
+    com: Option<&mut Handle<C>>,
+    len: *mut c_uint,
+) -> Result<*mut c_uchar> {
+    let com = handle(com)?;
+    check_null(len)?;
+    let data_len = com.peek_message()?.len();
+    let data_len = c_uint::try_from(data_len).map_err(|_| {
+        error::fail(
+            ErrorCode::InvalidData,
+            format!(
+                "Received {} bytes, which is too long for unsigned int",
+                data_len
+            ),
+        )
+    })?;
+    let data = com.take_message()?;
+
+    *len = data_len;
 
-    data.len() as i32
+    Ok(Box::into_raw(data.into_boxed_slice()) as *mut c_uchar)
 }
 
 pub(crate) unsafe fn send_table<C: Communicator>(
-    com: *mut c_void,
+    com: Option<&mut Handle<C>>,
     table: *const c_double,
     row_num: c_uint,
     col_num: c_uint,
-) -> c_int {
-    let mut com = Box::from_raw(com as *mut C);
+) -> Result<c_int> {
+    let com = handle(com)?;
+    check_null(table)?;
 
-    let table = from_raw_parts(table, (row_num * col_num) as usize);
+    let len = (row_num as usize)
+        .checked_mul(col_num as usize)
+        .ok_or_else(|| {
+            error::fail(
+                ErrorCode::InvalidArgument,
+                format!("A {}x{} table is too large", row_num, col_num),
+            )
+        })?;
+    let table = from_raw_parts(table, len);
 
-    let table = table
-        .chunks(col_num as usize)
-        .map(|row| row.to_vec())
-        .collect::<Vec<Vec<f64>>>();
+    let table = Matrix::new(row_num as usize, col_num as usize, table.to_vec())
+        .map_err(|e| error::fail(ErrorCode::InvalidArgument, e.to_string()))?;
 
-    let Ok(_) = Communicator::send_table(com.as_mut(), table) else {
-        return -1;
-    };
+    com.com.send_matrix(&table)?;
 
-    forget(com);
-
-    0
+    Ok(0)
 }
 
+// バッファが足りない場合、表は保留されるので`peek_table_shape`で大きさを確認して受信し直せる
 pub(crate) unsafe fn receive_table<C: Communicator>(
-    com: *mut c_void,
+    com: Option<&mut Handle<C>>,
     table_buf: *mut c_double,
     len: c_uint,
     row_num: *mut c_uint,
     col_num: *mut c_uint,
-) -> i32 {
-    let mut com = Box::from_raw(com as *mut C);
-
-    let Ok(table) = Communicator::receive_table(com.as_mut()) else {
-        return -1;
-    };
+) -> Result<i32> {
+    let com = handle(com)?;
+    check_null(table_buf)?;
+    check_null(row_num)?;
+    check_null(col_num)?;
 
-    let rn = table.len();
-    let cn = table.get(0).map(|r| r.len()).unwrap_or(0);
+    let (rn, cn) = com.peek_table()?.shape();
 
-    if len < (rn * cn) as u32 {
-        return -1;
+    if (len as usize) < rn * cn {
+        return Err(error::fail(
+            ErrorCode::BufferTooSmall,
+            format!(
+                "Received a {}x{} table but the buffer has only {} elements",
+                rn, cn, len
+            ),
+        ));
     }
 
+    let table = com.take_table()?;
     let table_buf = from_raw_parts_mut(table_buf, len as usize);
+    table_buf[..rn * cn].copy_from_slice(table.as_slice());
 
-    for (i, row) in table.iter().enumerate() {
-        for (j, b) in row.iter().enumerate() {
-            table_buf[i * row.len() + j] = *b;
-        }
-    }
+    *row_num = rn as u32;
+    *col_num = cn as u32;
+
+    Ok(0)
+}
+
+// 次の表の行数と列数。表は保留され、次の受信関数で渡される
+pub(crate) unsafe fn peek_table_shape<C: Communicator>(
+    com: Option<&mut Handle<C>>,
+    row_num: *mut c_uint,
+    col_num: *mut c_uint,
+) -> Result<c_int> {
+    let com = handle(com)?;
+    check_null(row_num)?;
+    check_null(col_num)?;
+
+    let (rn, cn) = com.peek_table()?.shape();
 
     *row_num = rn as u32;
     *col_num = cn as u32;
 
-    forget(com);
+    Ok(0)
+}
+
+// ライブラリ側で確保したバッファで受け取る。`free_table`で解放すること
+pub(crate) unsafe fn receive_table_alloc<C: Communicator>(
+    com: Option<&mut Handle<C>>,
+    row_num: *mut c_uint,
+    col_num: *mut c_uint,
+) -> Result<*mut c_double> {
+    let com = handle(com)?;
+    check_null(row_num)?;
+    check_null(col_num)?;
 
-    0
+    let table = com.take_table()?;
+    let (rn, cn) = table.shape();
+
+    *row_num = rn as u32;
+    *col_num = cn as u32;
+
+    Ok(Box::into_raw(table.into_vec().into_boxed_slice()) as *mut c_double)
 }
 
-pub(crate) unsafe fn close<C: Communicator>(com: *mut c_void) {
-    let _com = Box::from_raw(com as *mut C);
-    // drop(com.as_mut());
+pub(crate) unsafe fn free<T>(ptr: *mut T, len: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    let _buf = Box::from_raw(slice_from_raw_parts_mut(ptr, len));
 }
 
 /*
@@ -122,7 +291,7 @@ type RecvTableSig = unsafe extern "C" fn(*mut c_void, *mut f64, u32, *mut u32, *
 type ScenarioSig = unsafe extern "C" fn(*mut c_void, SendSig, RecvSig, SendTableSig, RecvTableSig);
 
 unsafe fn base<C: Communicator>(
-    com: *mut c_void,
+    com: Option<&mut Handle<C>>,
     scenario: *const c_void,
     // scenario: fn(SendSig, RecvSig, SendTableSig, RecvTableSig),
 ) {
@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::ffi::{c_char, CString};
+use std::fmt;
+use std::io::ErrorKind;
+
+// 失敗した関数は従来通り-1やNULLを返し、原因は`last_error_code`と`last_error_message`で取得する
+// どちらも呼び出したスレッドで最後に呼んだ関数の結果を返す
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Ok = 0,
+    /// 引数にNULLが渡された
+    NullPointer = 1,
+    /// 文字列がUTF-8でない、表の大きさが合わないなど
+    InvalidArgument = 2,
+    ConnectionRefused = 3,
+    /// 相手が切断した
+    ConnectionClosed = 4,
+    Timeout = 5,
+    /// 受信したデータが渡されたバッファに入らない
+    BufferTooSmall = 6,
+    /// 受信したデータが壊れている(JSONとして読めないなど)
+    InvalidData = 7,
+    /// その他の入出力エラー
+    Io = 8,
+}
+
+impl ErrorCode {
+    fn from_io(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::ConnectionRefused => ErrorCode::ConnectionRefused,
+            ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::NotConnected
+            | ErrorKind::UnexpectedEof => ErrorCode::ConnectionClosed,
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => ErrorCode::Timeout,
+            ErrorKind::InvalidInput => ErrorCode::InvalidArgument,
+            ErrorKind::InvalidData => ErrorCode::InvalidData,
+            _ => ErrorCode::Io,
+        }
+    }
+}
+
+// 原因をこちらで判断できるエラー
+#[derive(Debug)]
+struct Error {
+    code: ErrorCode,
+    message: String,
+}
+
+pub(crate) fn fail(code: ErrorCode, message: impl Into<String>) -> anyhow::Error {
+    Error {
+        code,
+        message: message.into(),
+    }
+    .into()
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+// 入出力以外で分類できないものは受信データの不備とみなす
+fn classify(err: &anyhow::Error) -> ErrorCode {
+    if let Some(err) = err.downcast_ref::<Error>() {
+        return err.code;
+    }
+
+    err.chain()
+        .find_map(|e| e.downcast_ref::<std::io::Error>())
+        .map(|e| ErrorCode::from_io(e.kind()))
+        .unwrap_or(ErrorCode::InvalidData)
+}
+
+struct LastError {
+    code: ErrorCode,
+    message: Option<CString>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<LastError> = const {
+        RefCell::new(LastError {
+            code: ErrorCode::Ok,
+            message: None,
+        })
+    };
+}
+
+fn set_last_error(code: ErrorCode, message: Option<String>) {
+    // メッセージ中のNULは取り除く
+    let message = message.map(|m| CString::new(m.replace('\0', "")).unwrap());
+
+    LAST_ERROR.with(|last| *last.borrow_mut() = LastError { code, message });
+}
+
+// 成功したら値を、失敗したらエラーを記録して`on_error`を返す
+pub(crate) fn report<T>(result: anyhow::Result<T>, on_error: T) -> T {
+    match result {
+        Ok(value) => {
+            set_last_error(ErrorCode::Ok, None);
+            value
+        }
+        Err(err) => {
+            set_last_error(classify(&err), Some(format!("{:#}", err)));
+            on_error
+        }
+    }
+}
+
+/// 呼び出したスレッドで最後に呼んだ関数の結果
+#[no_mangle]
+pub extern "C" fn last_error_code() -> ErrorCode {
+    LAST_ERROR.with(|last| last.borrow().code)
+}
+
+/// 返したポインタは同じスレッドで次に関数を呼ぶまで有効。エラーがなければNULL
+#[no_mangle]
+pub extern "C" fn last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .message
+            .as_ref()
+            .map_or(std::ptr::null(), |m| m.as_ptr())
+    })
+}
@@ -1,99 +1,393 @@
 mod base;
+mod error;
+mod options;
 
+pub use error::{last_error_code, last_error_message, ErrorCode};
+pub use options::{default_options, Options};
+
+use anyhow::Result;
+use base::Handle;
 use se_rust::client::TcpClient;
 use se_rust::server::TcpServer;
-use std::ffi::{c_char, c_double, c_int, c_uchar, c_uint, c_void, CStr};
+use std::ffi::{c_char, c_double, c_int, c_uchar, c_uint, c_ushort, CStr};
+
+// C側からは中身の見えないハンドル。ヘッダーでは`SeServer`、`SeClient`になる
+
+/// 予備校側の通信路。`new_server`で作り、`s_close`で解放する
+pub struct Server(Handle<TcpServer>);
+
+/// 中学側の通信路。`new_client`で作り、`c_close`で解放する
+pub struct Client(Handle<TcpClient>);
+
+unsafe fn server<'a>(com: *mut Server) -> Option<&'a mut Handle<TcpServer>> {
+    com.as_mut().map(|server| &mut server.0)
+}
+
+unsafe fn client<'a>(com: *mut Client) -> Option<&'a mut Handle<TcpClient>> {
+    com.as_mut().map(|client| &mut client.0)
+}
+
+// NULLなら既定値を使う
+unsafe fn optional_str<'a>(ptr: *const c_char) -> Result<Option<&'a str>> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
 
+    let s = CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|e| error::fail(ErrorCode::InvalidArgument, e.to_string()))?;
+
+    Ok(Some(s))
+}
+
+/// 中学側からの接続を待つ。失敗した場合はNULLを返す
 #[no_mangle]
-pub unsafe extern "C" fn new_server() -> *mut c_void {
-    let Ok(server) = TcpServer::new() else {
-        return std::ptr::null_mut();
+pub extern "C" fn new_server() -> *mut Server {
+    unsafe { new_server_with_options(std::ptr::null(), std::ptr::null()) }
+}
+
+/// addressとportで待ち受ける。addressがNULLならループバックアドレスを使う
+///
+/// # Safety
+///
+/// addressはNULLか、NUL終端の文字列であること
+#[no_mangle]
+pub unsafe extern "C" fn new_server_at(address: *const c_char, port: c_ushort) -> *mut Server {
+    let options = Options {
+        port,
+        ..Options::default()
     };
 
-    Box::into_raw(Box::new(server)) as *mut c_void
+    new_server_with_options(address, &options)
+}
+
+/// optionsがNULLなら既定の設定を使う
+///
+/// # Safety
+///
+/// addressはNULLか、NUL終端の文字列であること
+/// optionsはNULLか、`default_options`で初期化した`SeOptions`を指すこと
+#[no_mangle]
+pub unsafe extern "C" fn new_server_with_options(
+    address: *const c_char,
+    options: *const Options,
+) -> *mut Server {
+    error::report(listen(address, options), std::ptr::null_mut())
+}
+
+unsafe fn listen(address: *const c_char, options: *const Options) -> Result<*mut Server> {
+    let address = optional_str(address)?;
+    let server = Options::from_ptr(options)?
+        .server_builder(address)
+        .build()?;
+
+    Ok(Box::into_raw(Box::new(Server(Handle::new(server)))))
 }
 
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+/// dataはNULLか、lenバイト読めること
 #[no_mangle]
-pub unsafe extern "C" fn s_send(com: *mut c_void, data: *const c_uchar, len: c_uint) -> c_int {
-    base::send::<TcpServer>(com, data, len)
+pub unsafe extern "C" fn s_send(com: *mut Server, data: *const c_uchar, len: c_uint) -> c_int {
+    error::report(base::send(server(com), data, len), -1)
 }
 
+/// 受信したバイト数を返す。バッファが足りない場合は-1を返し、メッセージは保留される
+///
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+/// bufはNULLか、lenバイト書き込めること
 #[no_mangle]
-pub unsafe fn s_receive(com: *mut c_void, buf: *mut c_uchar, len: c_uint) -> c_int {
-    base::receive::<TcpServer>(com, buf, len)
+pub unsafe extern "C" fn s_receive(com: *mut Server, buf: *mut c_uchar, len: c_uint) -> c_int {
+    error::report(base::receive(server(com), buf, len), -1)
 }
 
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+/// tableはNULLか、row_num * col_num個読めること
 #[no_mangle]
-pub unsafe fn s_send_table(
-    com: *mut c_void,
+pub unsafe extern "C" fn s_send_table(
+    com: *mut Server,
     table: *const c_double,
     row_num: c_uint,
     col_num: c_uint,
 ) -> c_int {
-    base::send_table::<TcpServer>(com, table, row_num, col_num)
+    error::report(base::send_table(server(com), table, row_num, col_num), -1)
 }
 
+/// バッファが足りない場合は-1を返し、表は保留される
+///
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+/// table_bufはNULLか、len個書き込めること。row_numとcol_numはNULLか書き込めること
 #[no_mangle]
-pub unsafe fn s_receive_table(
-    com: *mut c_void,
+pub unsafe extern "C" fn s_receive_table(
+    com: *mut Server,
     table_buf: *mut c_double,
     len: c_uint,
     row_num: *mut c_uint,
     col_num: *mut c_uint,
-) -> i32 {
-    base::receive_table::<TcpServer>(com, table_buf, len, row_num, col_num)
+) -> c_int {
+    error::report(
+        base::receive_table(server(com), table_buf, len, row_num, col_num),
+        -1,
+    )
 }
 
+/// 次のメッセージのバイト数を返す。メッセージは次の受信関数で渡される
+///
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
 #[no_mangle]
-pub unsafe fn s_close(com: *mut c_void) {
-    base::close::<TcpServer>(com)
+pub unsafe extern "C" fn s_peek_length(com: *mut Server) -> c_int {
+    error::report(base::peek_length(server(com)), -1)
 }
 
+/// ライブラリ側で確保したバッファで受け取る。`free_buffer`で解放すること
+///
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+/// lenはNULLか書き込めること
 #[no_mangle]
-pub unsafe extern "C" fn new_client(server_address: *const c_char) -> *mut c_void {
-    let Ok(server_address) = CStr::from_ptr(server_address).to_str() else {
-        return std::ptr::null_mut();
-    };
+pub unsafe extern "C" fn s_receive_alloc(com: *mut Server, len: *mut c_uint) -> *mut c_uchar {
+    error::report(base::receive_alloc(server(com), len), std::ptr::null_mut())
+}
+
+/// 次の表の行数と列数を返す。表は次の受信関数で渡される
+///
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+/// row_numとcol_numはNULLか書き込めること
+#[no_mangle]
+pub unsafe extern "C" fn s_peek_table_shape(
+    com: *mut Server,
+    row_num: *mut c_uint,
+    col_num: *mut c_uint,
+) -> c_int {
+    error::report(base::peek_table_shape(server(com), row_num, col_num), -1)
+}
+
+/// ライブラリ側で確保したバッファで受け取る。`free_table`で解放すること
+///
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+/// row_numとcol_numはNULLか書き込めること
+#[no_mangle]
+pub unsafe extern "C" fn s_receive_table_alloc(
+    com: *mut Server,
+    row_num: *mut c_uint,
+    col_num: *mut c_uint,
+) -> *mut c_double {
+    error::report(
+        base::receive_table_alloc(server(com), row_num, col_num),
+        std::ptr::null_mut(),
+    )
+}
+
+/// # Safety
+///
+/// comはNULLか、`new_server*`が返してまだ`s_close`していないハンドルであること
+/// 閉じたハンドルは二度と使わないこと
+#[no_mangle]
+pub unsafe extern "C" fn s_close(com: *mut Server) {
+    if !com.is_null() {
+        drop(Box::from_raw(com));
+    }
+}
+
+/// 予備校側に接続する。失敗した場合はNULLを返す
+///
+/// # Safety
+///
+/// server_addressはNULLか、NUL終端の文字列であること
+#[no_mangle]
+pub unsafe extern "C" fn new_client(server_address: *const c_char) -> *mut Client {
+    new_client_with_options(server_address, std::ptr::null())
+}
 
-    let Ok(client) = TcpClient::new(server_address) else {
-        return std::ptr::null_mut();
+/// # Safety
+///
+/// server_addressはNULLか、NUL終端の文字列であること
+#[no_mangle]
+pub unsafe extern "C" fn new_client_at(
+    server_address: *const c_char,
+    port: c_ushort,
+) -> *mut Client {
+    let options = Options {
+        port,
+        ..Options::default()
     };
 
-    Box::into_raw(Box::new(client)) as *mut c_void
+    new_client_with_options(server_address, &options)
+}
+
+/// optionsがNULLなら既定の設定を使う
+///
+/// # Safety
+///
+/// server_addressはNULLか、NUL終端の文字列であること
+/// optionsはNULLか、`default_options`で初期化した`SeOptions`を指すこと
+#[no_mangle]
+pub unsafe extern "C" fn new_client_with_options(
+    server_address: *const c_char,
+    options: *const Options,
+) -> *mut Client {
+    error::report(connect(server_address, options), std::ptr::null_mut())
+}
+
+unsafe fn connect(server_address: *const c_char, options: *const Options) -> Result<*mut Client> {
+    base::check_null(server_address)?;
+    let server_address = optional_str(server_address)?.unwrap();
+    let client = Options::from_ptr(options)?
+        .client_builder(server_address)
+        .build()?;
+
+    Ok(Box::into_raw(Box::new(Client(Handle::new(client)))))
 }
 
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+/// dataはNULLか、lenバイト読めること
 #[no_mangle]
-pub unsafe extern "C" fn c_send(com: *mut c_void, data: *const c_uchar, len: c_uint) -> c_int {
-    base::send::<TcpClient>(com, data, len)
+pub unsafe extern "C" fn c_send(com: *mut Client, data: *const c_uchar, len: c_uint) -> c_int {
+    error::report(base::send(client(com), data, len), -1)
 }
 
+/// 受信したバイト数を返す。バッファが足りない場合は-1を返し、メッセージは保留される
+///
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+/// bufはNULLか、lenバイト書き込めること
 #[no_mangle]
-pub unsafe fn c_receive(com: *mut c_void, buf: *mut c_uchar, len: c_uint) -> c_int {
-    base::receive::<TcpClient>(com, buf, len)
+pub unsafe extern "C" fn c_receive(com: *mut Client, buf: *mut c_uchar, len: c_uint) -> c_int {
+    error::report(base::receive(client(com), buf, len), -1)
 }
 
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+/// tableはNULLか、row_num * col_num個読めること
 #[no_mangle]
-pub unsafe fn c_send_table(
-    com: *mut c_void,
+pub unsafe extern "C" fn c_send_table(
+    com: *mut Client,
     table: *const c_double,
     row_num: c_uint,
     col_num: c_uint,
 ) -> c_int {
-    base::send_table::<TcpClient>(com, table, row_num, col_num)
+    error::report(base::send_table(client(com), table, row_num, col_num), -1)
 }
 
+/// バッファが足りない場合は-1を返し、表は保留される
+///
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+/// table_bufはNULLか、len個書き込めること。row_numとcol_numはNULLか書き込めること
 #[no_mangle]
-pub unsafe fn c_receive_table(
-    com: *mut c_void,
+pub unsafe extern "C" fn c_receive_table(
+    com: *mut Client,
     table_buf: *mut c_double,
     len: c_uint,
     row_num: *mut c_uint,
     col_num: *mut c_uint,
-) -> i32 {
-    base::receive_table::<TcpClient>(com, table_buf, len, row_num, col_num)
+) -> c_int {
+    error::report(
+        base::receive_table(client(com), table_buf, len, row_num, col_num),
+        -1,
+    )
+}
+
+/// 次のメッセージのバイト数を返す。メッセージは次の受信関数で渡される
+///
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+#[no_mangle]
+pub unsafe extern "C" fn c_peek_length(com: *mut Client) -> c_int {
+    error::report(base::peek_length(client(com)), -1)
+}
+
+/// ライブラリ側で確保したバッファで受け取る。`free_buffer`で解放すること
+///
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+/// lenはNULLか書き込めること
+#[no_mangle]
+pub unsafe extern "C" fn c_receive_alloc(com: *mut Client, len: *mut c_uint) -> *mut c_uchar {
+    error::report(base::receive_alloc(client(com), len), std::ptr::null_mut())
+}
+
+/// 次の表の行数と列数を返す。表は次の受信関数で渡される
+///
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+/// row_numとcol_numはNULLか書き込めること
+#[no_mangle]
+pub unsafe extern "C" fn c_peek_table_shape(
+    com: *mut Client,
+    row_num: *mut c_uint,
+    col_num: *mut c_uint,
+) -> c_int {
+    error::report(base::peek_table_shape(client(com), row_num, col_num), -1)
+}
+
+/// ライブラリ側で確保したバッファで受け取る。`free_table`で解放すること
+///
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+/// row_numとcol_numはNULLか書き込めること
+#[no_mangle]
+pub unsafe extern "C" fn c_receive_table_alloc(
+    com: *mut Client,
+    row_num: *mut c_uint,
+    col_num: *mut c_uint,
+) -> *mut c_double {
+    error::report(
+        base::receive_table_alloc(client(com), row_num, col_num),
+        std::ptr::null_mut(),
+    )
+}
+
+/// # Safety
+///
+/// comはNULLか、`new_client*`が返してまだ`c_close`していないハンドルであること
+/// 閉じたハンドルは二度と使わないこと
+#[no_mangle]
+pub unsafe extern "C" fn c_close(com: *mut Client) {
+    if !com.is_null() {
+        drop(Box::from_raw(com));
+    }
+}
+
+/// `*_receive_alloc`で受け取ったバッファを解放する。lenは受け取ったバイト数
+///
+/// # Safety
+///
+/// bufはNULLか、`*_receive_alloc`が返してまだ解放していないバッファであること。lenはそのとき受け取った長さ
+#[no_mangle]
+pub unsafe extern "C" fn free_buffer(buf: *mut c_uchar, len: c_uint) {
+    base::free(buf, len as usize)
 }
 
+/// `*_receive_table_alloc`で受け取った表を解放する
+///
+/// # Safety
+///
+/// tableはNULLか、`*_receive_table_alloc`が返してまだ解放していない表であること。row_numとcol_numはそのとき受け取った大きさ
 #[no_mangle]
-pub unsafe fn c_close(com: *mut c_void) {
-    base::close::<TcpClient>(com)
+pub unsafe extern "C" fn free_table(table: *mut c_double, row_num: c_uint, col_num: c_uint) {
+    base::free(table, row_num as usize * col_num as usize)
 }
@@ -0,0 +1,127 @@
+use crate::error::{self, ErrorCode};
+use anyhow::Result;
+use se_rust::client::{RetryPolicy, TcpClient, TcpClientBuilder};
+use se_rust::server::{TcpServer, TcpServerBuilder, PORT};
+use std::ffi::{c_int, c_uint, c_ushort};
+use std::mem::offset_of;
+use std::ptr;
+use std::time::Duration;
+
+/// 接続の設定。`default_options`で既定値を取得してから必要な項目だけ変更すること
+/// 時間はミリ秒で、0なら無制限
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// 構造体のバイト数。`default_options`が設定するので変更しないこと
+    /// 項目を増やしたときに、古いヘッダーでビルドされたプログラムを見分けるのに使う
+    pub size: c_uint,
+    /// 予備校側が待ち受ける(中学側が接続する)ポート
+    pub port: c_ushort,
+    pub read_timeout_ms: c_uint,
+    pub write_timeout_ms: c_uint,
+    /// 中学側のみ
+    pub connect_timeout_ms: c_uint,
+    /// 0以外なら、予備校側が起動するまで接続を再試行する(中学側のみ)
+    pub retry: c_int,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            size: size_of::<Options>() as c_uint,
+            port: PORT,
+            read_timeout_ms: 0,
+            write_timeout_ms: 0,
+            connect_timeout_ms: 0,
+            retry: 0,
+        }
+    }
+}
+
+fn timeout(ms: c_uint) -> Option<Duration> {
+    (ms != 0).then(|| Duration::from_millis(ms as u64))
+}
+
+// これまでのバージョンの構造体のバイト数。項目は末尾にだけ追加するので、各項目の手前で切った大きさになる
+const KNOWN_SIZES: [usize; 5] = [
+    offset_of!(Options, read_timeout_ms),
+    offset_of!(Options, write_timeout_ms),
+    offset_of!(Options, connect_timeout_ms),
+    offset_of!(Options, retry),
+    size_of::<Options>(),
+];
+
+impl Options {
+    /// 呼び出し側の構造体を読む。NULLなら既定値を使う
+    /// 先頭の`size`だけを読んでから、その大きさの分だけを既定値に上書きする
+    /// 古いヘッダーでビルドされた小さい構造体では、足りない項目が既定値になる
+    ///
+    /// # Safety
+    ///
+    /// optionsはNULLか、先頭の`size`バイトが読める`Options`を指すこと
+    pub(crate) unsafe fn from_ptr(options: *const Options) -> Result<Options> {
+        let mut result = Options::default();
+        if options.is_null() {
+            return Ok(result);
+        }
+
+        let size = ptr::addr_of!((*options).size).read() as usize;
+        if !KNOWN_SIZES.contains(&size) {
+            return Err(error::fail(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "Options has {} bytes but this library expects {}; initialize it with default_options",
+                    size,
+                    size_of::<Options>()
+                ),
+            ));
+        }
+
+        ptr::copy_nonoverlapping(
+            options.cast::<u8>(),
+            ptr::addr_of_mut!(result).cast::<u8>(),
+            size,
+        );
+        result.size = size_of::<Options>() as c_uint;
+
+        Ok(result)
+    }
+
+    pub(crate) fn server_builder(&self, address: Option<&str>) -> TcpServerBuilder {
+        let mut builder = TcpServer::builder().port(self.port);
+        if let Some(address) = address {
+            builder = builder.address(address);
+        }
+        if let Some(timeout) = timeout(self.read_timeout_ms) {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = timeout(self.write_timeout_ms) {
+            builder = builder.write_timeout(timeout);
+        }
+
+        builder
+    }
+
+    pub(crate) fn client_builder(&self, server_address: &str) -> TcpClientBuilder {
+        let mut builder = TcpClient::builder(server_address).port(self.port);
+        if let Some(timeout) = timeout(self.read_timeout_ms) {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = timeout(self.write_timeout_ms) {
+            builder = builder.write_timeout(timeout);
+        }
+        if let Some(timeout) = timeout(self.connect_timeout_ms) {
+            builder = builder.connect_timeout(timeout);
+        }
+        if self.retry != 0 {
+            builder = builder.retry(RetryPolicy::default());
+        }
+
+        builder
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn default_options() -> Options {
+    Options::default()
+}
@@ -0,0 +1,241 @@
+use numpy::ndarray::Array2;
+use numpy::{IntoPyArray, PyArray2, PyReadonlyArray2};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyList, PyTuple};
+use se_rust::client::{RetryPolicy, TcpClient};
+use se_rust::comm::Communicator;
+use se_rust::matrix::Matrix;
+use se_rust::server::{TcpServer, PORT};
+use std::time::Duration;
+
+// se_rustのPythonバインディング
+//
+//   server = se_python.Server(port=10000)
+//   client = se_python.Client("127.0.0.1", port=10000)
+//   client.send_table([[1.0, 2.0], [3.0, 4.0]])
+//   table = server.receive_table()
+//
+// 通信中はGILを解放するので、他のPythonスレッドは動き続ける
+// numpyは任意の依存で、配列を送る場合と`receive_array`を使う場合だけ必要になる
+
+// 入出力エラーは対応するPythonの例外(TimeoutError、ConnectionRefusedErrorなど)にする
+fn to_py_err(err: anyhow::Error) -> PyErr {
+    match err.downcast::<std::io::Error>() {
+        Ok(err) => err.into(),
+        Err(err) => PyRuntimeError::new_err(format!("{:#}", err)),
+    }
+}
+
+fn timeout(seconds: Option<f64>) -> PyResult<Option<Duration>> {
+    seconds
+        .map(|s| {
+            Duration::try_from_secs_f64(s)
+                .map_err(|_| PyValueError::new_err(format!("Invalid timeout: {}", s)))
+        })
+        .transpose()
+}
+
+// 文字列はUTF-8で送る
+#[derive(FromPyObject)]
+enum Message {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Message {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Message::Text(text) => text.as_bytes(),
+            Message::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+// リストのリストかnumpyの2次元配列
+// リストの場合はnumpyを使わないので、numpyがなくても送れる
+fn extract_table(table: &Bound<'_, PyAny>) -> PyResult<Matrix> {
+    let matrix = if table.is_instance_of::<PyList>() || table.is_instance_of::<PyTuple>() {
+        Matrix::from_rows(table.extract::<Vec<Vec<f64>>>()?)
+    } else if let Ok(array) = table.extract::<PyReadonlyArray2<'_, f64>>() {
+        let array = array.as_array();
+        let (rows, cols) = array.dim();
+        Matrix::new(rows, cols, array.iter().copied().collect())
+    } else {
+        // float64以外の配列などは行のシーケンスとして読む
+        Matrix::from_rows(table.extract::<Vec<Vec<f64>>>()?)
+    };
+
+    matrix.map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+fn send<C: Communicator + Send>(py: Python<'_>, comm: &mut C, data: Message) -> PyResult<()> {
+    py.detach(|| comm.send(data.as_bytes()))?;
+
+    Ok(())
+}
+
+fn receive<'py, C: Communicator + Send>(
+    py: Python<'py>,
+    comm: &mut C,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let data = py.detach(|| comm.receive())?;
+
+    Ok(PyBytes::new(py, &data))
+}
+
+fn send_table<C: Communicator + Send>(
+    py: Python<'_>,
+    comm: &mut C,
+    table: &Bound<'_, PyAny>,
+) -> PyResult<()> {
+    let matrix = extract_table(table)?;
+
+    py.detach(|| comm.send_matrix(&matrix)).map_err(to_py_err)
+}
+
+fn receive_matrix<C: Communicator + Send>(py: Python<'_>, comm: &mut C) -> PyResult<Matrix> {
+    py.detach(|| comm.receive_matrix()).map_err(to_py_err)
+}
+
+fn into_array(py: Python<'_>, matrix: Matrix) -> Bound<'_, PyArray2<f64>> {
+    let shape = matrix.shape();
+
+    Array2::from_shape_vec(shape, matrix.into_vec())
+        .unwrap()
+        .into_pyarray(py)
+}
+
+/// 予備校側。作成時に中学側からの接続を待つ
+#[pyclass]
+struct Server(TcpServer);
+
+#[pymethods]
+impl Server {
+    #[new]
+    #[pyo3(signature = (port = PORT, address = None, read_timeout = None, write_timeout = None))]
+    fn new(
+        py: Python<'_>,
+        port: u16,
+        address: Option<String>,
+        read_timeout: Option<f64>,
+        write_timeout: Option<f64>,
+    ) -> PyResult<Self> {
+        let mut builder = TcpServer::builder().port(port);
+        if let Some(address) = address {
+            builder = builder.address(&address);
+        }
+        if let Some(timeout) = timeout(read_timeout)? {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = timeout(write_timeout)? {
+            builder = builder.write_timeout(timeout);
+        }
+
+        let server = py.detach(|| builder.build()).map_err(to_py_err)?;
+
+        Ok(Self(server))
+    }
+
+    /// bytesかstrを送る
+    fn send(&mut self, py: Python<'_>, data: Message) -> PyResult<()> {
+        send(py, &mut self.0, data)
+    }
+
+    fn receive<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        receive(py, &mut self.0)
+    }
+
+    /// リストのリストかnumpyの2次元配列を送る
+    fn send_table(&mut self, py: Python<'_>, table: &Bound<'_, PyAny>) -> PyResult<()> {
+        send_table(py, &mut self.0, table)
+    }
+
+    /// リストのリストで受け取る
+    fn receive_table(&mut self, py: Python<'_>) -> PyResult<Vec<Vec<f64>>> {
+        Ok(receive_matrix(py, &mut self.0)?.into_rows())
+    }
+
+    /// numpyの2次元配列で受け取る
+    fn receive_array<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyArray2<f64>>> {
+        Ok(into_array(py, receive_matrix(py, &mut self.0)?))
+    }
+}
+
+/// 中学側。作成時に予備校側に接続する
+#[pyclass]
+struct Client(TcpClient);
+
+#[pymethods]
+impl Client {
+    #[new]
+    #[pyo3(signature = (
+        server_address,
+        port = PORT,
+        read_timeout = None,
+        write_timeout = None,
+        connect_timeout = None,
+        retry = false
+    ))]
+    fn new(
+        py: Python<'_>,
+        server_address: &str,
+        port: u16,
+        read_timeout: Option<f64>,
+        write_timeout: Option<f64>,
+        connect_timeout: Option<f64>,
+        retry: bool,
+    ) -> PyResult<Self> {
+        let mut builder = TcpClient::builder(server_address).port(port);
+        if let Some(timeout) = timeout(read_timeout)? {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = timeout(write_timeout)? {
+            builder = builder.write_timeout(timeout);
+        }
+        if let Some(timeout) = timeout(connect_timeout)? {
+            builder = builder.connect_timeout(timeout);
+        }
+        // 予備校側が起動するまで再試行する
+        if retry {
+            builder = builder.retry(RetryPolicy::default());
+        }
+
+        let client = py.detach(|| builder.build()).map_err(to_py_err)?;
+
+        Ok(Self(client))
+    }
+
+    /// bytesかstrを送る
+    fn send(&mut self, py: Python<'_>, data: Message) -> PyResult<()> {
+        send(py, &mut self.0, data)
+    }
+
+    fn receive<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        receive(py, &mut self.0)
+    }
+
+    /// リストのリストかnumpyの2次元配列を送る
+    fn send_table(&mut self, py: Python<'_>, table: &Bound<'_, PyAny>) -> PyResult<()> {
+        send_table(py, &mut self.0, table)
+    }
+
+    /// リストのリストで受け取る
+    fn receive_table(&mut self, py: Python<'_>) -> PyResult<Vec<Vec<f64>>> {
+        Ok(receive_matrix(py, &mut self.0)?.into_rows())
+    }
+
+    /// numpyの2次元配列で受け取る
+    fn receive_array<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyArray2<f64>>> {
+        Ok(into_array(py, receive_matrix(py, &mut self.0)?))
+    }
+}
+
+#[pymodule]
+fn se_python(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("PORT", PORT)?;
+    m.add_class::<Server>()?;
+    m.add_class::<Client>()?;
+
+    Ok(())
+}
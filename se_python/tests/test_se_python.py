@@ -0,0 +1,62 @@
+# 実行: pip install ".[numpy]" pytest && pytest tests
+# numpyがなくてもリストの送受信は確認する
+
+import threading
+import unittest
+
+import se_python
+
+try:
+    import numpy
+except ImportError:
+    numpy = None
+
+
+def connect(port):
+    # Serverは接続を待つ間GILを解放するので、別スレッドで作ってからClientで接続する
+    servers = []
+    thread = threading.Thread(target=lambda: servers.append(se_python.Server(port=port)))
+    thread.start()
+    client = se_python.Client("127.0.0.1", port=port, retry=True)
+    thread.join()
+
+    return servers[0], client
+
+
+class SePythonTest(unittest.TestCase):
+    def test_send_receive(self):
+        server, client = connect(10030)
+
+        client.send("こんにちは")
+        self.assertEqual(server.receive(), "こんにちは".encode())
+        server.send(b"\x00\xffbytes")
+        self.assertEqual(client.receive(), b"\x00\xffbytes")
+
+    def test_send_table_list(self):
+        server, client = connect(10031)
+
+        client.send_table([[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]])
+        self.assertEqual(server.receive_table(), [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]])
+        server.send_table(((0.5, -1.0),))
+        self.assertEqual(client.receive_table(), [[0.5, -1.0]])
+
+        with self.assertRaises(ValueError):
+            client.send_table([[1.0, 2.0], [3.0]])
+
+    @unittest.skipIf(numpy is None, "numpy is not installed")
+    def test_send_table_numpy(self):
+        server, client = connect(10032)
+
+        array = numpy.arange(6, dtype=numpy.float64).reshape(2, 3)
+        client.send_table(array)
+        received = server.receive_array()
+        self.assertEqual(received.shape, (2, 3))
+        self.assertTrue(numpy.array_equal(received, array))
+
+        # float64以外の配列も送れる
+        server.send_table(numpy.array([[1, 2], [3, 4]], dtype=numpy.int32))
+        self.assertEqual(client.receive_table(), [[1.0, 2.0], [3.0, 4.0]])
+
+
+if __name__ == "__main__":
+    unittest.main()
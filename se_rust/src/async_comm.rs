@@ -0,0 +1,200 @@
+use crate::codec::Codec;
+use crate::comm::{encode_frame, unescape, unescape_line, Framing};
+use crate::matrix::{self, Element, Matrix};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{ErrorKind, Result};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+// `Communicator`のasync版
+// `TcpCommunicator`と同じフレーミングを使うので、相手が同期版でも通信できる
+#[async_trait]
+pub trait AsyncCommunicator: Send {
+    async fn send(&mut self, data: &[u8]) -> Result<()>;
+
+    async fn receive(&mut self) -> Result<Vec<u8>>;
+
+    fn codec(&self) -> Codec {
+        Codec::default()
+    }
+
+    async fn send_table(&mut self, table: Vec<Vec<f64>>) -> anyhow::Result<()> {
+        let data = matrix::encode_table(self.codec(), table)?;
+        self.send(&data).await?;
+
+        Ok(())
+    }
+
+    async fn receive_table(&mut self) -> anyhow::Result<Vec<Vec<f64>>> {
+        let data = self.receive().await?;
+
+        matrix::decode_table(self.codec(), &data)
+    }
+
+    async fn send_matrix<T: Element>(&mut self, matrix: &Matrix<T>) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        let data = matrix::encode_matrix(self.codec(), matrix)?;
+        self.send(&data).await?;
+
+        Ok(())
+    }
+
+    async fn receive_matrix<T: Element>(&mut self) -> anyhow::Result<Matrix<T>>
+    where
+        Self: Sized,
+    {
+        let data = self.receive().await?;
+
+        matrix::decode_matrix(self.codec(), &data)
+    }
+
+    async fn send_value<T>(&mut self, value: &T) -> anyhow::Result<()>
+    where
+        T: Serialize + Sync + ?Sized,
+        Self: Sized,
+    {
+        let data = self.codec().encode(value)?;
+        self.send(&data).await?;
+
+        Ok(())
+    }
+
+    async fn receive_value<T>(&mut self) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+        Self: Sized,
+    {
+        let data = self.receive().await?;
+
+        self.codec().decode(&data)
+    }
+}
+
+pub struct AsyncTcpCommunicator {
+    sender: OwnedWriteHalf,
+    receiver: BufReader<OwnedReadHalf>,
+    framing: Framing,
+    codec: Codec,
+}
+
+impl AsyncTcpCommunicator {
+    pub fn new(stream: TcpStream, framing: Framing) -> Self {
+        let (receiver, sender) = stream.into_split();
+
+        Self {
+            sender,
+            receiver: BufReader::new(receiver),
+            framing,
+            codec: Codec::default(),
+        }
+    }
+
+    pub async fn connect<A: ToSocketAddrs>(addr: A, framing: Framing) -> Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+
+        Ok(Self::new(stream, framing))
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+}
+
+#[async_trait]
+impl AsyncCommunicator for AsyncTcpCommunicator {
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let frame = encode_frame(self.framing, data)?;
+        self.sender.write_all(&frame).await?;
+        self.sender.flush().await?;
+
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Vec<u8>> {
+        match self.framing {
+            Framing::Line => {
+                let mut buf = Vec::new();
+                self.receiver.read_until(b'\n', &mut buf).await?;
+
+                Ok(unescape_line(buf))
+            }
+            Framing::EscapedLine => {
+                let mut buf = Vec::new();
+                let len = self.receiver.read_until(b'\n', &mut buf).await?;
+                if len == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+
+                unescape(&buf)
+            }
+            Framing::LengthPrefixed => {
+                let len = self.receiver.read_u32().await? as u64;
+
+                let mut data = Vec::new();
+                (&mut self.receiver)
+                    .take(len)
+                    .read_to_end(&mut data)
+                    .await?;
+                if (data.len() as u64) < len {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+
+                Ok(data)
+            }
+        }
+    }
+}
+
+// チャネル上ではメッセージ単位で送れるのでフレーミングは不要
+pub struct AsyncChannelCommunicator {
+    rx: UnboundedReceiver<Vec<u8>>,
+    tx: UnboundedSender<Vec<u8>>,
+    codec: Codec,
+}
+
+impl AsyncChannelCommunicator {
+    pub fn new(rx: UnboundedReceiver<Vec<u8>>, tx: UnboundedSender<Vec<u8>>) -> Self {
+        Self {
+            rx,
+            tx,
+            codec: Codec::default(),
+        }
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+}
+
+#[async_trait]
+impl AsyncCommunicator for AsyncChannelCommunicator {
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.tx
+            .send(data.to_vec())
+            .map_err(|_| ErrorKind::BrokenPipe)?;
+
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Vec<u8>> {
+        self.rx.recv().await.ok_or(ErrorKind::BrokenPipe.into())
+    }
+}
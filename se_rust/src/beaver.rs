@@ -0,0 +1,348 @@
+use crate::comm::Communicator;
+use crate::matrix::Matrix;
+use crate::server::TcpServerListener;
+use crate::sharing::{self, Field, Party, Share};
+use anyhow::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// Beaver triple (a, b, c = ab) を使ったシェア同士の乗算
+// triple はディーラー(信頼できる第三者)が作って両者に配る
+
+// 1回の要求で配るtripleの上限
+pub const MAX_TRIPLES: usize = 1024;
+// 1回の要求で配るtripleの要素数(a、b、cの合計)の上限
+pub const MAX_TRIPLE_ELEMENTS: usize = 1 << 20;
+
+// 乗算の種類と入力の形
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TripleShape {
+    // rows x cols の行列同士の要素ごとの積
+    Elementwise(usize, usize),
+    // n x k の行列と k x m の行列の積
+    Matrix(usize, usize, usize),
+}
+
+impl TripleShape {
+    fn operands(&self) -> ((usize, usize), (usize, usize)) {
+        match *self {
+            TripleShape::Elementwise(rows, cols) => ((rows, cols), (rows, cols)),
+            TripleShape::Matrix(n, k, m) => ((n, k), (k, m)),
+        }
+    }
+
+    fn result(&self) -> (usize, usize) {
+        match *self {
+            TripleShape::Elementwise(rows, cols) => (rows, cols),
+            TripleShape::Matrix(n, _, m) => (n, m),
+        }
+    }
+
+    // 1つのtripleの要素数。桁あふれする場合はNone
+    fn element_count(&self) -> Option<usize> {
+        let ((a_rows, a_cols), (b_rows, b_cols)) = self.operands();
+        let (c_rows, c_cols) = self.result();
+
+        a_rows
+            .checked_mul(a_cols)?
+            .checked_add(b_rows.checked_mul(b_cols)?)?
+            .checked_add(c_rows.checked_mul(c_cols)?)
+    }
+}
+
+// 形と個数は相手から受け取ることもあるので、作る前に上限を確認する
+fn check_request(shape: TripleShape, count: usize) -> Result<()> {
+    if count > MAX_TRIPLES {
+        return Err(anyhow::anyhow!(
+            "Cannot deal {} triples at once (at most {})",
+            count,
+            MAX_TRIPLES
+        ));
+    }
+
+    let elements = shape
+        .element_count()
+        .and_then(|n| n.checked_mul(count))
+        .filter(|&n| n <= MAX_TRIPLE_ELEMENTS);
+    if elements.is_none() {
+        return Err(anyhow::anyhow!(
+            "{} triples of shape {:?} exceed {} elements",
+            count,
+            shape,
+            MAX_TRIPLE_ELEMENTS
+        ));
+    }
+
+    Ok(())
+}
+
+// 片方の参加者が持つtripleのシェア
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Triple {
+    pub shape: TripleShape,
+    pub a: Share,
+    pub b: Share,
+    pub c: Share,
+}
+
+impl Triple {
+    fn validate(&self, field: Field) -> Result<()> {
+        let (a_shape, b_shape) = self.shape.operands();
+        if self.a.shape() != a_shape
+            || self.b.shape() != b_shape
+            || self.c.shape() != self.shape.result()
+        {
+            return Err(anyhow::anyhow!(
+                "Triple does not have the shape {:?}",
+                self.shape
+            ));
+        }
+
+        self.a.validate(field)?;
+        self.b.validate(field)?;
+        self.c.validate(field)
+    }
+}
+
+// ディーラーなしで、手元でtripleを作って両者のシェアに分ける
+pub fn generate_triples<R: Rng + ?Sized>(
+    field: Field,
+    shape: TripleShape,
+    count: usize,
+    rng: &mut R,
+) -> Result<(Vec<Triple>, Vec<Triple>)> {
+    check_request(shape, count)?;
+    let ((a_rows, a_cols), (b_rows, b_cols)) = shape.operands();
+
+    let mut server = Vec::with_capacity(count);
+    let mut client = Vec::with_capacity(count);
+    for _ in 0..count {
+        let a = field.random_matrix(a_rows, a_cols, rng)?;
+        let b = field.random_matrix(b_rows, b_cols, rng)?;
+        let c = match shape {
+            TripleShape::Elementwise(..) => field.mul_elementwise(&a, &b)?,
+            TripleShape::Matrix(..) => field.mul_matrix(&a, &b)?,
+        };
+
+        let (a_s, a_c) = sharing::split(&a, field, rng)?;
+        let (b_s, b_c) = sharing::split(&b, field, rng)?;
+        let (c_s, c_c) = sharing::split(&c, field, rng)?;
+        server.push(Triple {
+            shape,
+            a: a_s,
+            b: b_s,
+            c: c_s,
+        });
+        client.push(Triple {
+            shape,
+            a: a_c,
+            b: b_c,
+            c: c_c,
+        });
+    }
+
+    Ok((server, client))
+}
+
+// 参加者からディーラーへの要求
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TripleRequest {
+    party: Party,
+    field: Field,
+    shape: TripleShape,
+    count: usize,
+}
+
+// ディーラーから参加者への返事
+// 要求を断った場合も理由を返し、参加者が返事を待ち続けないようにする
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum DealerReply {
+    Triples(Vec<Triple>),
+    Rejected(String),
+}
+
+// ディーラーにtripleを要求する。両者が同じ形と個数で呼ぶ必要がある
+pub fn request_triples<D: Communicator>(
+    dealer: &mut D,
+    party: Party,
+    field: Field,
+    shape: TripleShape,
+    count: usize,
+) -> Result<Vec<Triple>> {
+    check_request(shape, count)?;
+    dealer.send_value(&TripleRequest {
+        party,
+        field,
+        shape,
+        count,
+    })?;
+
+    let triples = match dealer.receive_value()? {
+        DealerReply::Triples(triples) => triples,
+        DealerReply::Rejected(reason) => {
+            return Err(anyhow::anyhow!("Dealer rejected the request: {}", reason))
+        }
+    };
+    if triples.len() != count {
+        return Err(anyhow::anyhow!(
+            "Requested {} triples but received {}",
+            count,
+            triples.len()
+        ));
+    }
+    for triple in &triples {
+        if triple.shape != shape {
+            return Err(anyhow::anyhow!(
+                "Requested triples of shape {:?} but received {:?}",
+                shape,
+                triple.shape
+            ));
+        }
+        triple.validate(field)?;
+    }
+
+    Ok(triples)
+}
+
+fn receive_requests<C1, C2>(first: &mut C1, second: &mut C2) -> Result<TripleRequest>
+where
+    C1: Communicator,
+    C2: Communicator,
+{
+    let r1: TripleRequest = first.receive_value()?;
+    let r2: TripleRequest = second.receive_value()?;
+    if r1.party == r2.party {
+        return Err(anyhow::anyhow!(
+            "Both peers requested triples as {:?}",
+            r1.party
+        ));
+    }
+    if (r1.field, r1.shape, r1.count) != (r2.field, r2.shape, r2.count) {
+        return Err(anyhow::anyhow!(
+            "Requests do not match: {:?} and {:?}",
+            r1,
+            r2
+        ));
+    }
+
+    Ok(r1)
+}
+
+// ディーラーの処理。両者から要求を1回ずつ受け取り、tripleを配る
+// どちらの通信路が予備校側でも構わない
+// 法が素数でない、上限を超えるなどの要求にはtripleを配らず、両者に理由を返してからエラーを返す
+pub fn deal<C1, C2, R>(first: &mut C1, second: &mut C2, rng: &mut R) -> Result<()>
+where
+    C1: Communicator,
+    C2: Communicator,
+    R: Rng + ?Sized,
+{
+    let triples = receive_requests(first, second).and_then(|r1| {
+        let (server, client) = generate_triples(r1.field, r1.shape, r1.count, rng)?;
+        Ok(match r1.party {
+            Party::Server => (server, client),
+            Party::Client => (client, server),
+        })
+    });
+
+    let (t1, t2) = match triples {
+        Ok(triples) => triples,
+        Err(e) => {
+            // 相手がすでに切断していても、もう一方には知らせる
+            let reply = DealerReply::Rejected(e.to_string());
+            let _ = first.send_value(&reply);
+            let _ = second.send_value(&reply);
+            return Err(e);
+        }
+    };
+    first.send_value(&DealerReply::Triples(t1))?;
+    second.send_value(&DealerReply::Triples(t2))?;
+
+    Ok(())
+}
+
+// TCPでディーラーを動かす。2者からの接続を待ち、要求に1回応える
+pub fn serve<R: Rng + ?Sized>(listener: &TcpServerListener, rng: &mut R) -> Result<()> {
+    let mut first = listener.accept()?;
+    let mut second = listener.accept()?;
+
+    deal(&mut first.server, &mut second.server, rng)
+}
+
+fn check_operands(triple: &Triple, x: &Share, y: &Share) -> Result<()> {
+    let (x_shape, y_shape) = triple.shape.operands();
+    if x.shape() != x_shape || y.shape() != y_shape {
+        return Err(anyhow::anyhow!(
+            "Operands of shape {:?} and {:?} do not match the triple {:?}",
+            x.shape(),
+            y.shape(),
+            triple.shape
+        ));
+    }
+
+    Ok(())
+}
+
+// d = x - a, e = y - b を公開する
+fn open_masked<C: Communicator>(
+    comm: &mut C,
+    triple: &Triple,
+    x: &Share,
+    y: &Share,
+) -> Result<(Matrix<u64>, Matrix<u64>)> {
+    check_operands(triple, x, y)?;
+    let d = sharing::open(comm, &x.sub(&triple.a)?)?;
+    let e = sharing::open(comm, &y.sub(&triple.b)?)?;
+
+    Ok((d, e))
+}
+
+// シェア同士の要素ごとの積。tripleは1回しか使ってはいけない
+// xy = c + db + ea + de で、公開値deは予備校側だけが足す
+pub fn multiply<C: Communicator>(
+    comm: &mut C,
+    party: Party,
+    x: &Share,
+    y: &Share,
+    triple: &Triple,
+) -> Result<Share> {
+    if !matches!(triple.shape, TripleShape::Elementwise(..)) {
+        return Err(anyhow::anyhow!(
+            "Triple of shape {:?} cannot be used for elementwise multiplication",
+            triple.shape
+        ));
+    }
+    let (d, e) = open_masked(comm, triple, x, y)?;
+    let field = x.field();
+
+    let db = field.mul_elementwise(&d, triple.b.values())?;
+    let ea = field.mul_elementwise(&e, triple.a.values())?;
+    let values = field.add_matrix(&field.add_matrix(triple.c.values(), &db)?, &ea)?;
+
+    Share::new(field, values)?.add_public(&field.mul_elementwise(&d, &e)?, party)
+}
+
+// シェア同士の行列積。tripleは1回しか使ってはいけない
+// XY = C + DB + AE + DE で、公開値DEは予備校側だけが足す
+pub fn matrix_multiply<C: Communicator>(
+    comm: &mut C,
+    party: Party,
+    x: &Share,
+    y: &Share,
+    triple: &Triple,
+) -> Result<Share> {
+    if !matches!(triple.shape, TripleShape::Matrix(..)) {
+        return Err(anyhow::anyhow!(
+            "Triple of shape {:?} cannot be used for matrix multiplication",
+            triple.shape
+        ));
+    }
+    let (d, e) = open_masked(comm, triple, x, y)?;
+    let field = x.field();
+
+    let db = field.mul_matrix(&d, triple.b.values())?;
+    let ae = field.mul_matrix(triple.a.values(), &e)?;
+    let values = field.add_matrix(&field.add_matrix(triple.c.values(), &db)?, &ae)?;
+
+    Share::new(field, values)?.add_public(&field.mul_matrix(&d, &e)?, party)
+}
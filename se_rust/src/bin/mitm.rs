@@ -0,0 +1,105 @@
+use anyhow::Result;
+use se_rust::client::{RetryPolicy, TcpClient};
+use se_rust::codec::Codec;
+use se_rust::comm::Framing;
+use se_rust::mitm::{self, Rules};
+use se_rust::server::{TcpServer, PORT};
+
+// 中間者攻撃のデモ
+// 中学側はこのプログラムに接続し、このプログラムが本物の予備校側に接続して中継する
+//
+//   mitm [--listen PORT] [--listen-address HOST] [--server HOST] [--port PORT] [--rules FILE] [--framing NAME] [--codec NAME]
+
+const USAGE: &str = "usage: mitm [--listen PORT] [--listen-address HOST] [--server HOST] [--port PORT] [--rules FILE] [--framing line|escaped-line|length-prefixed] [--codec json|msgpack|cbor|bincode]";
+
+// 中学側を待ち受ける既定のポート
+const LISTEN_PORT: u16 = 10001;
+
+struct Options {
+    listen: u16,
+    // 指定しなければループバックアドレスで待ち受ける
+    // 別のマシンの中学側から接続させる場合は"0.0.0.0"などを指定する
+    listen_address: Option<String>,
+    server: String,
+    port: u16,
+    rules: Rules,
+    framing: Framing,
+    codec: Codec,
+}
+
+fn parse_args() -> Result<Options> {
+    let mut options = Options {
+        listen: LISTEN_PORT,
+        listen_address: None,
+        server: "127.0.0.1".to_string(),
+        port: PORT,
+        rules: Rules::default(),
+        framing: Framing::default(),
+        codec: Codec::default(),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("{} requires a value\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--listen" => options.listen = value()?.parse()?,
+            "--listen-address" => options.listen_address = Some(value()?),
+            "--server" => options.server = value()?,
+            "--port" => options.port = value()?.parse()?,
+            "--rules" => options.rules = Rules::from_file(value()?)?,
+            "--framing" => {
+                let name = value()?;
+                options.framing = Framing::from_name(&name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown framing {:?}", name))?;
+            }
+            "--codec" => {
+                let name = value()?;
+                options.codec = Codec::from_name(&name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown codec {:?}", name))?;
+            }
+            _ => return Err(anyhow::anyhow!("Unknown argument {:?}\n{}", arg, USAGE)),
+        }
+    }
+    if !options.codec.fits(options.framing) {
+        return Err(anyhow::anyhow!(
+            "Codec {} cannot be used with {} framing\n{}",
+            options.codec.name(),
+            options.framing.name(),
+            USAGE
+        ));
+    }
+
+    Ok(options)
+}
+
+fn main() -> Result<()> {
+    let options = parse_args()?;
+    for rule in options.rules.rules() {
+        println!("Rule: {:?}", rule);
+    }
+
+    let mut builder = TcpServer::builder().port(options.listen);
+    if let Some(address) = &options.listen_address {
+        builder = builder.address(address);
+    }
+    println!(
+        "Waiting for a client on {}:{}",
+        options.listen_address.as_deref().unwrap_or("localhost"),
+        options.listen
+    );
+    let client = builder
+        .framing(options.framing)
+        .codec(options.codec)
+        .build()?;
+    let server = TcpClient::builder(&options.server)
+        .port(options.port)
+        .framing(options.framing)
+        .codec(options.codec)
+        .retry(RetryPolicy::default())
+        .build()?;
+
+    mitm::run(client, server, options.rules)
+}
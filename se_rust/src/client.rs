@@ -1,11 +1,30 @@
-use crate::comm::{ChannelCommunicator, Communicator, TcpCommunicator};
+use crate::codec::Codec;
+use crate::comm::{
+    ChannelCommunicator, Communicator, CommunicatorCore, Framing, IpVersion, TcpCommunicator,
+};
+use crate::secure::{EncryptedCommunicator, Handshake};
+use crate::server::PORT;
+#[cfg(feature = "tls")]
+use crate::tls::{Fingerprint, TlsClient, TlsCommunicator};
 use anyhow::Result;
 use crossbeam_channel::{Receiver, Sender};
 use std::io::BufReader;
 use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct Client<C: Communicator>(C);
 
+impl<C: Communicator> Client<C> {
+    pub fn get_ref(&self) -> &C {
+        &self.0
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.0
+    }
+}
+
 impl<C> Communicator for Client<C>
 where
     C: Communicator,
@@ -17,22 +36,269 @@ where
     fn receive(&mut self) -> std::io::Result<Vec<u8>> {
         self.0.receive()
     }
+
+    fn codec(&self) -> Codec {
+        self.0.codec()
+    }
+
+    fn set_codec(&mut self, codec: Codec) -> std::io::Result<()> {
+        self.0.set_codec(codec)
+    }
 }
 
-const PORT: &'static str = "10000";
+impl<C> CommunicatorCore for Client<C>
+where
+    C: Communicator + CommunicatorCore,
+{
+    type Sender = C::Sender;
+    type Receiver = C::Receiver;
+
+    fn get_sender(&mut self) -> &mut Self::Sender {
+        self.0.get_sender()
+    }
+
+    fn get_receiver(&mut self) -> &mut BufReader<Self::Receiver> {
+        self.0.get_receiver()
+    }
+
+    fn framing(&self) -> Framing {
+        self.0.framing()
+    }
+
+    fn set_framing(&mut self, framing: Framing) {
+        self.0.set_framing(framing)
+    }
+
+    fn partial_frame(&mut self) -> Option<&mut Vec<u8>> {
+        self.0.partial_frame()
+    }
+
+    fn read_timeout(&self) -> std::io::Result<Option<Duration>> {
+        self.0.read_timeout()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        CommunicatorCore::set_read_timeout(&mut self.0, timeout)
+    }
+}
 
 pub type TcpClient = Client<TcpCommunicator>;
 
 impl TcpClient {
     pub fn new(server_address: &str) -> Result<TcpClient> {
-        let stream = TcpStream::connect(format!("{}:{}", server_address, PORT))?;
-        println!("Connection to {:?}", server_address);
-        let receiver = BufReader::new(stream.try_clone()?);
+        Self::builder(server_address).build()
+    }
+
+    pub fn with_framing(server_address: &str, framing: Framing) -> Result<TcpClient> {
+        Self::builder(server_address).framing(framing).build()
+    }
+
+    pub fn builder(server_address: &str) -> TcpClientBuilder {
+        TcpClientBuilder::new(server_address)
+    }
+}
+
+// サーバーより先にクライアントを起動しても繋がるよう、失敗したら間隔を伸ばしながら再接続する
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    // 0以上の有限な値。間隔はmax_delayで頭打ちになる
+    pub multiplier: f64,
+    // 最初の試行からこの時間を過ぎたら諦める
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            multiplier: 2.0,
+            deadline: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TcpClientBuilder {
+    server_address: String,
+    port: u16,
+    ip_version: IpVersion,
+    framing: Framing,
+    codec: Codec,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retry: Option<RetryPolicy>,
+}
+
+impl TcpClientBuilder {
+    pub fn new(server_address: &str) -> Self {
+        Self {
+            server_address: server_address.to_string(),
+            port: PORT,
+            ip_version: IpVersion::default(),
+            framing: Framing::default(),
+            codec: Codec::default(),
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            retry: None,
+        }
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.ip_version = ip_version;
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn connect(&self, connect_timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+        let addrs = self.ip_version.resolve(&self.server_address, self.port)?;
+
+        let Some(timeout) = connect_timeout else {
+            return TcpStream::connect(&addrs[..]);
+        };
+
+        let mut last_err = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap())
+    }
+
+    fn connect_with_retry(&self, retry: &RetryPolicy) -> Result<TcpStream> {
+        // 負やNaNの倍率では間隔が決まらないので受け付けない
+        if !retry.multiplier.is_finite() || retry.multiplier < 0.0 {
+            return Err(anyhow::anyhow!(
+                "Invalid retry multiplier: {}",
+                retry.multiplier
+            ));
+        }
 
-        Ok(Client(TcpCommunicator {
-            sender: stream,
-            receiver,
-        }))
+        let start = Instant::now();
+        let mut delay = retry.initial_delay;
+        let mut attempts = 0;
+        let mut last_err = None;
+
+        loop {
+            // 期限を過ぎてから接続しない(0のタイムアウトは`connect_timeout`がエラーにする)
+            let remaining = retry.deadline.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                break;
+            }
+
+            // 1回の試行で期限を超えて待たないよう、タイムアウト未指定でも残り時間で打ち切る
+            attempts += 1;
+            let timeout = self.connect_timeout.map_or(remaining, |t| t.min(remaining));
+            let err = match self.connect(Some(timeout)) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => e,
+            };
+
+            println!(
+                "Connection attempt {} to {}:{} failed: {}",
+                attempts, self.server_address, self.port, err
+            );
+            last_err = Some(err);
+
+            if retry.deadline.saturating_sub(start.elapsed()) <= delay {
+                break;
+            }
+            thread::sleep(delay);
+            // 倍率が大きすぎてDurationに収まらない場合は上限に張り付かせる
+            delay = Duration::try_from_secs_f64(delay.as_secs_f64() * retry.multiplier)
+                .map_or(retry.max_delay, |d| d.min(retry.max_delay));
+        }
+
+        let err = last_err.unwrap_or_else(|| std::io::ErrorKind::TimedOut.into());
+        Err(anyhow::Error::new(err).context(format!(
+            "Could not connect to {}:{} after {} attempts",
+            self.server_address, self.port, attempts
+        )))
+    }
+
+    fn open_stream(&self) -> Result<TcpStream> {
+        let stream = match &self.retry {
+            Some(retry) => self.connect_with_retry(retry)?,
+            None => self.connect(self.connect_timeout)?,
+        };
+        println!("Connection to {:?}", self.server_address);
+
+        Ok(stream)
+    }
+
+    pub fn build(self) -> Result<TcpClient> {
+        let stream = self.open_stream()?;
+
+        let mut comm = TcpCommunicator::new(stream, self.framing)?;
+        comm.set_codec(self.codec)?;
+        comm.set_read_timeout(self.read_timeout)?;
+        comm.set_write_timeout(self.write_timeout)?;
+
+        Ok(Client(comm))
+    }
+
+    // 接続後にX25519の鍵交換を行い、暗号化された通信路を返す
+    pub fn build_encrypted(
+        self,
+        handshake: &Handshake,
+    ) -> Result<EncryptedCommunicator<TcpClient>> {
+        EncryptedCommunicator::with_handshake(self.build()?, handshake)
+    }
+
+    // 接続後にTLSのハンドシェイクを行う
+    // サーバーの証明書は事前に受け取ったフィンガープリントと一致するものだけを受け入れる
+    #[cfg(feature = "tls")]
+    pub fn build_tls(self, pinned: Fingerprint) -> Result<TlsClient> {
+        let stream = self.open_stream()?;
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+
+        let mut comm =
+            TlsCommunicator::connect(stream, &self.server_address, pinned, self.framing)?;
+        comm.set_codec(self.codec)?;
+
+        Ok(Client(comm))
     }
 }
 
@@ -43,4 +309,13 @@ impl ChannelClient {
         let cc = ChannelCommunicator::new(rx, tx);
         Self(cc)
     }
+
+    pub fn with_framing(
+        rx: Receiver<Vec<u8>>,
+        tx: Sender<Vec<u8>>,
+        framing: Framing,
+    ) -> ChannelClient {
+        let cc = ChannelCommunicator::with_framing(rx, tx, framing);
+        Self(cc)
+    }
 }
@@ -0,0 +1,144 @@
+use crate::comm::{Communicator, CommunicatorCore, Framing};
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// 表や値をバイト列にする方式
+// JSON以外はバイナリになるので、`Framing::Line`ではなく
+// `Framing::EscapedLine`か`Framing::LengthPrefixed`と組み合わせること(`set_codec`がエラーにする)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// 従来の形式。表は数値を文字列にしたJSONで送る(Go版と互換)
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+    /// bincodeによる最もコンパクトな形式
+    Bincode,
+}
+
+impl Codec {
+    // ネゴシエーションで優先する順
+    const PREFERENCE: [Codec; 4] = [Codec::Bincode, Codec::MessagePack, Codec::Cbor, Codec::Json];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::MessagePack => "msgpack",
+            Codec::Cbor => "cbor",
+            Codec::Bincode => "bincode",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        Self::PREFERENCE.into_iter().find(|c| c.name() == name)
+    }
+
+    // このフレーミングで壊れずに送れるか。`Framing::Line`は改行を区切りにするのでJSONしか通らない
+    pub fn fits(&self, framing: Framing) -> bool {
+        *self == Codec::Json || framing != Framing::Line
+    }
+
+    pub(crate) fn check_framing(&self, framing: Framing) -> std::io::Result<()> {
+        if self.fits(framing) {
+            return Ok(());
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "codec {} needs EscapedLine or LengthPrefixed framing, not {}",
+                self.name(),
+                framing.name()
+            ),
+        ))
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        let data = match self {
+            Codec::Json => line_safe_json(serde_json::to_vec(value)?),
+            Codec::MessagePack => rmp_serde::to_vec(value)?,
+            Codec::Cbor => {
+                let mut data = Vec::new();
+                ciborium::ser::into_writer(value, &mut data)?;
+                data
+            }
+            Codec::Bincode => bincode::serialize(value)?,
+        };
+
+        Ok(data)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        let value = match self {
+            Codec::Json => serde_json::from_slice(data)?,
+            Codec::MessagePack => rmp_serde::from_slice(data)?,
+            Codec::Cbor => ciborium::de::from_reader(data)?,
+            Codec::Bincode => bincode::deserialize(data)?,
+        };
+
+        Ok(value)
+    }
+}
+
+// `Framing::Line`の受信側は`\n`と`\r`を改行に戻してしまうので、JSONの同じ意味のエスケープに置き換える
+// `\\`(バックスラッシュ自体)の後の文字は別のエスケープなので、2文字ずつ読んで区別する
+fn line_safe_json(data: Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+
+    let mut iter = data.into_iter();
+    while let Some(b) = iter.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match iter.next() {
+            Some(b'n') => out.extend_from_slice(b"\\u000a"),
+            Some(b'r') => out.extend_from_slice(b"\\u000d"),
+            Some(b) => out.extend_from_slice(&[b'\\', b]),
+            None => out.push(b'\\'),
+        }
+    }
+
+    out
+}
+
+const CODEC_HELLO: &str = "se-codec";
+
+// 双方が対応しているコーデックのうち最も優先度の高いものに切り替える
+// 今のフレーミングで送れないコーデックは提案しない
+pub fn negotiate_codec<C>(comm: &mut C, supported: &[Codec]) -> Result<Codec>
+where
+    C: Communicator + CommunicatorCore + ?Sized,
+{
+    let framing = comm.framing();
+    let supported = supported
+        .iter()
+        .copied()
+        .filter(|c| c.fits(framing))
+        .collect::<Vec<_>>();
+    let hello = std::iter::once(CODEC_HELLO)
+        .chain(supported.iter().map(Codec::name))
+        .collect::<Vec<_>>()
+        .join(" ");
+    comm.send(hello.as_bytes())?;
+
+    let peer_hello = comm.receive()?;
+    let peer_hello = String::from_utf8(peer_hello)?;
+    let mut words = peer_hello.split(' ');
+    if words.next() != Some(CODEC_HELLO) {
+        return Err(anyhow::anyhow!(
+            "Peer did not answer codec negotiation: {:?}",
+            peer_hello
+        ));
+    }
+    let peer_supported = words.filter_map(Codec::from_name).collect::<Vec<_>>();
+
+    let codec = Codec::PREFERENCE
+        .into_iter()
+        .find(|c| supported.contains(c) && peer_supported.contains(c))
+        .ok_or_else(|| anyhow::anyhow!("No codec supported by both peers"))?;
+    comm.set_codec(codec)?;
+
+    Ok(codec)
+}
@@ -1,7 +1,170 @@
-use crate::matrix;
-use crossbeam_channel::{Receiver, Sender};
-use std::io::{BufRead, BufReader, Read, Result, Write};
-use std::net::TcpStream;
+use crate::codec::Codec;
+use crate::matrix::{self, Element, Matrix};
+use crossbeam_channel::{Receiver, RecvTimeoutError, SendTimeoutError, Sender};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    /// 改行区切り。`\r`と`\n`をエスケープして送る(Go版と互換)
+    #[default]
+    Line,
+    /// 改行区切り。`\\`もエスケープするので任意のバイト列を往復できる
+    EscapedLine,
+    /// 4バイトのビッグエンディアンの長さを先頭に付けて生のバイト列を送る
+    LengthPrefixed,
+}
+
+impl Framing {
+    // ネゴシエーションで優先する順
+    const PREFERENCE: [Framing; 3] = [Framing::LengthPrefixed, Framing::EscapedLine, Framing::Line];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Framing::Line => "line",
+            Framing::EscapedLine => "escaped-line",
+            Framing::LengthPrefixed => "length-prefixed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Framing> {
+        Self::PREFERENCE.into_iter().find(|f| f.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IpVersion {
+    #[default]
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    pub(crate) fn localhost(&self) -> &'static str {
+        match self {
+            IpVersion::V4 => "127.0.0.1",
+            IpVersion::V6 => "::1",
+        }
+    }
+
+    pub(crate) fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addrs = (host, port)
+            .to_socket_addrs()?
+            .filter(|addr| match self {
+                IpVersion::V4 => addr.is_ipv4(),
+                IpVersion::V6 => addr.is_ipv6(),
+            })
+            .collect::<Vec<_>>();
+
+        if addrs.is_empty() {
+            return Err(Error::new(
+                ErrorKind::AddrNotAvailable,
+                format!("No {:?} address found for {}", self, host),
+            ));
+        }
+
+        Ok(addrs)
+    }
+}
+
+pub(crate) fn escape_line(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .flat_map(|&b| match b {
+            b'\r' => vec![b'\\', b'r'],
+            b'\n' => vec![b'\\', b'n'],
+            b => vec![b],
+        })
+        .collect()
+}
+
+pub(crate) fn unescape_line(mut buf: Vec<u8>) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    buf.reverse();
+    while let Some(b) = buf.pop() {
+        match b {
+            b'\\' => match buf.pop() {
+                Some(b'r') => data.push(b'\r'),
+                Some(b'n') => data.push(b'\n'),
+                Some(b) => {
+                    data.push(b'\\');
+                    data.push(b);
+                }
+                None => {
+                    data.push(b'\\');
+                    break;
+                }
+            },
+            b'\n' => (),
+            b => data.push(b),
+        }
+    }
+
+    data
+}
+
+pub(crate) fn escape(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .flat_map(|&b| match b {
+            b'\\' => vec![b'\\', b'\\'],
+            b'\r' => vec![b'\\', b'r'],
+            b'\n' => vec![b'\\', b'n'],
+            b => vec![b],
+        })
+        .collect()
+}
+
+pub(crate) fn unescape(buf: &[u8]) -> Result<Vec<u8>> {
+    let buf = buf.strip_suffix(b"\n").unwrap_or(buf);
+    let mut data = Vec::with_capacity(buf.len());
+
+    let mut iter = buf.iter();
+    while let Some(&b) = iter.next() {
+        match b {
+            b'\\' => match iter.next() {
+                Some(b'\\') => data.push(b'\\'),
+                Some(b'r') => data.push(b'\r'),
+                Some(b'n') => data.push(b'\n'),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "invalid escape sequence",
+                    ))
+                }
+            },
+            b => data.push(b),
+        }
+    }
+
+    Ok(data)
+}
+
+pub(crate) fn encode_frame(framing: Framing, data: &[u8]) -> Result<Vec<u8>> {
+    match framing {
+        Framing::Line => {
+            let mut frame = escape_line(data);
+            frame.push(b'\n');
+            Ok(frame)
+        }
+        Framing::EscapedLine => {
+            let mut frame = escape(data);
+            frame.push(b'\n');
+            Ok(frame)
+        }
+        Framing::LengthPrefixed => {
+            let len = u32::try_from(data.len()).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, "message is too long to be framed")
+            })?;
+            let mut frame = Vec::with_capacity(4 + data.len());
+            frame.extend(len.to_be_bytes());
+            frame.extend(data);
+            Ok(frame)
+        }
+    }
+}
 
 pub trait CommunicatorCore {
     type Sender: Write;
@@ -9,53 +172,178 @@ pub trait CommunicatorCore {
 
     fn get_sender(&mut self) -> &mut Self::Sender;
     fn get_receiver(&mut self) -> &mut BufReader<Self::Receiver>;
+    fn framing(&self) -> Framing;
+    fn set_framing(&mut self, framing: Framing);
+
+    // 受信のタイムアウト。対応していない通信路ではエラーを返す
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "this communicator has no read timeout",
+        ))
+    }
 
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> Result<()> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "this communicator has no read timeout",
+        ))
+    }
+
+    // 1メッセージ分のフレームを書き込む
     fn write(&mut self, data: &[u8]) -> Result<()> {
-        let data = data
-            .iter()
-            .map(|&b| match b {
-                b'\r' => vec![b'\\', b'r'],
-                b'\n' => vec![b'\\', b'n'],
-                b => vec![b],
-            })
-            .flatten()
-            .collect::<Vec<_>>();
+        let frame = encode_frame(self.framing(), data)?;
 
         let sender = self.get_sender();
-        sender.write_all(&data)?;
+        sender.write_all(&frame)?;
         sender.flush()?;
 
         Ok(())
     }
 
+    // タイムアウトで途中まで読んだフレームを保持する場所。Noneなら保持しない
+    fn partial_frame(&mut self) -> Option<&mut Vec<u8>> {
+        None
+    }
+
+    // 1メッセージ分のフレームを読み込む
+    // タイムアウトした場合は途中まで読んだ分を`partial_frame`に残し、次の呼び出しで続きから読む
     fn read(&mut self) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
-        let len = self.get_receiver().read_until(b'\n', &mut buf)?;
-        buf = buf[..len].to_vec();
-
-        let mut data = Vec::new();
-
-        buf.reverse();
-        while let Some(b) = buf.pop() {
-            match b {
-                b'\\' => match buf.pop() {
-                    Some(b'r') => data.push(b'\r'),
-                    Some(b'n') => data.push(b'\n'),
-                    Some(b) => {
-                        data.push(b'\\');
-                        data.push(b);
-                    }
-                    None => {
-                        data.push(b'\\');
-                        break;
-                    }
-                },
-                b'\n' => (),
-                b => data.push(b),
+        let framing = self.framing();
+        let mut buf = self.partial_frame().map(std::mem::take).unwrap_or_default();
+
+        if let Err(e) = read_frame(framing, self.get_receiver(), &mut buf) {
+            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+                if let Some(partial) = self.partial_frame() {
+                    *partial = buf;
+                }
             }
+            return Err(e);
         }
 
-        Ok(data)
+        match framing {
+            Framing::Line => Ok(unescape_line(buf)),
+            Framing::EscapedLine => {
+                if buf.is_empty() {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+
+                unescape(&buf)
+            }
+            Framing::LengthPrefixed => Ok(buf.split_off(4)),
+        }
+    }
+}
+
+// 1フレーム分をbufに読み込む。bufに途中まで読んだフレームがあれば続きから読む
+fn read_frame<R: BufRead>(framing: Framing, receiver: &mut R, buf: &mut Vec<u8>) -> Result<()> {
+    match framing {
+        Framing::Line | Framing::EscapedLine => {
+            receiver.read_until(b'\n', buf)?;
+        }
+        Framing::LengthPrefixed => {
+            read_up_to(receiver, buf, 4)?;
+            let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+
+            // 長さを信用して先に確保はしない
+            read_up_to(receiver, buf, 4 + len)?;
+        }
+    }
+
+    Ok(())
+}
+
+// bufがlenバイトになるまで読む
+fn read_up_to<R: Read>(receiver: &mut R, buf: &mut Vec<u8>, len: usize) -> Result<()> {
+    let remaining = len.saturating_sub(buf.len()) as u64;
+    receiver.take(remaining).read_to_end(buf)?;
+    if buf.len() < len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(())
+}
+
+const FRAMING_HELLO: &str = "se-framing";
+
+// 旧形式しか話せない相手は返事をしないので、この時間待っても返事がなければ`Framing::Line`にする
+pub const FRAMING_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+// 双方が対応しているフレーミングのうち最も優先度の高いものに切り替える
+// ネゴシエーション自体は現在のフレーミング(通常は`Framing::Line`)で行う
+pub fn negotiate_framing<C>(comm: &mut C, supported: &[Framing]) -> anyhow::Result<Framing>
+where
+    C: Communicator + CommunicatorCore + ?Sized,
+{
+    negotiate_framing_with_timeout(comm, supported, FRAMING_NEGOTIATION_TIMEOUT)
+}
+
+// 相手がtimeoutまでに返事をしなければ、ネゴシエーションしない旧形式の相手とみなして`Framing::Line`にする
+// その場合、こちらの送った提案は相手に通常のメッセージとして1つ届く
+// 受信のタイムアウトに対応していない通信路では返事が来るまで待つ
+pub fn negotiate_framing_with_timeout<C>(
+    comm: &mut C,
+    supported: &[Framing],
+    timeout: Duration,
+) -> anyhow::Result<Framing>
+where
+    C: Communicator + CommunicatorCore + ?Sized,
+{
+    let hello = std::iter::once(FRAMING_HELLO)
+        .chain(supported.iter().map(Framing::name))
+        .collect::<Vec<_>>()
+        .join(" ");
+    comm.send(hello.as_bytes())?;
+
+    let peer_hello = match comm.read_timeout() {
+        Ok(previous) => {
+            comm.set_read_timeout(Some(timeout))?;
+            let peer_hello = comm.receive();
+            comm.set_read_timeout(previous)?;
+            match peer_hello {
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    comm.set_framing(Framing::Line);
+                    return Ok(Framing::Line);
+                }
+                peer_hello => peer_hello?,
+            }
+        }
+        Err(_) => comm.receive()?,
+    };
+    let peer_hello = String::from_utf8(peer_hello)?;
+    let mut words = peer_hello.split(' ');
+    if words.next() != Some(FRAMING_HELLO) {
+        return Err(anyhow::anyhow!(
+            "Peer did not answer framing negotiation: {:?}",
+            peer_hello
+        ));
+    }
+    let peer_supported = words.filter_map(Framing::from_name).collect::<Vec<_>>();
+
+    let framing = Framing::PREFERENCE
+        .into_iter()
+        .find(|f| supported.contains(f) && peer_supported.contains(f))
+        .ok_or_else(|| anyhow::anyhow!("No framing supported by both peers"))?;
+    comm.set_framing(framing);
+
+    Ok(framing)
+}
+
+// タイムアウトによるエラーかどうか
+// 通信関数のエラーは`std::io::ErrorKind::TimedOut`として返る
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<Error>()
+        .map(|e| e.kind() == ErrorKind::TimedOut)
+        .unwrap_or(false)
+}
+
+// ソケットのタイムアウトはOSによってWouldBlockで返ってくるのでTimedOutに揃える
+pub(crate) fn normalize_timeout(err: Error) -> Error {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            Error::new(ErrorKind::TimedOut, "communication timed out")
+        }
+        _ => err,
     }
 }
 
@@ -65,6 +353,21 @@ pub trait Communicator {
     // channelでrecvを挟むために用意した
     fn receive(&mut self) -> Result<Vec<u8>>;
 
+    fn codec(&self) -> Codec {
+        Codec::default()
+    }
+
+    fn set_codec(&mut self, codec: Codec) -> Result<()> {
+        if codec == self.codec() {
+            return Ok(());
+        }
+
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "this communicator cannot change its codec",
+        ))
+    }
+
     fn send_table(&mut self, table: Vec<Vec<f64>>) -> anyhow::Result<()> {
         matrix::send_table(self, table)
     }
@@ -72,11 +375,77 @@ pub trait Communicator {
     fn receive_table(&mut self) -> anyhow::Result<Vec<Vec<f64>>> {
         matrix::receive_table(self)
     }
+
+    // f64以外にも`Element`を実装した整数型の行列を送れる
+    fn send_matrix<T: Element>(&mut self, matrix: &Matrix<T>) -> anyhow::Result<()>
+    where
+        Self: Sized,
+    {
+        matrix::send_matrix(self, matrix)
+    }
+
+    fn receive_matrix<T: Element>(&mut self) -> anyhow::Result<Matrix<T>>
+    where
+        Self: Sized,
+    {
+        matrix::receive_matrix(self)
+    }
+
+    // 任意のserde対応の型を`codec()`でエンコードして送る
+    // JSONの改行のエスケープは`\u000a`にして送るので、`Framing::Line`でも文字列中の改行が壊れない
+    fn send_value<T>(&mut self, value: &T) -> anyhow::Result<()>
+    where
+        T: Serialize + ?Sized,
+        Self: Sized,
+    {
+        let data = self.codec().encode(value)?;
+        self.send(&data)?;
+
+        Ok(())
+    }
+
+    fn receive_value<T>(&mut self) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+        Self: Sized,
+    {
+        let data = self.receive()?;
+
+        self.codec().decode(&data)
+    }
 }
 
 pub struct TcpCommunicator {
     pub sender: TcpStream,
     pub receiver: BufReader<TcpStream>,
+    framing: Framing,
+    codec: Codec,
+    partial: Vec<u8>,
+}
+
+impl TcpCommunicator {
+    /// 接続済みのストリームから作る。受信側はストリームを複製して作る
+    /// フレーミングとコーデックを持つため、構造体リテラルでは作れない
+    pub fn new(stream: TcpStream, framing: Framing) -> Result<Self> {
+        let receiver = BufReader::new(stream.try_clone()?);
+
+        Ok(Self {
+            sender: stream,
+            receiver,
+            framing,
+            codec: Codec::default(),
+            partial: Vec::new(),
+        })
+    }
+
+    // タイムアウトした場合、途中まで受信したメッセージは保持され、次の受信で続きから読む
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.sender.set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.sender.set_write_timeout(timeout)
+    }
 }
 
 impl CommunicatorCore for TcpCommunicator {
@@ -90,20 +459,45 @@ impl CommunicatorCore for TcpCommunicator {
     fn get_receiver(&mut self) -> &mut BufReader<Self::Receiver> {
         &mut self.receiver
     }
+
+    fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    fn partial_frame(&mut self) -> Option<&mut Vec<u8>> {
+        Some(&mut self.partial)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.sender.read_timeout()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        TcpCommunicator::set_read_timeout(self, timeout)
+    }
 }
 
 impl Communicator for TcpCommunicator {
     fn send(&mut self, data: &[u8]) -> Result<()> {
-        self.write(data)?;
-        let sender = self.get_sender();
-        sender.write_all(b"\n")?;
-        sender.flush()?;
-
-        Ok(())
+        self.write(data).map_err(normalize_timeout)
     }
 
     fn receive(&mut self) -> Result<Vec<u8>> {
-        self.read()
+        self.read().map_err(normalize_timeout)
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn set_codec(&mut self, codec: Codec) -> Result<()> {
+        codec.check_framing(self.framing)?;
+        self.codec = codec;
+        Ok(())
     }
 }
 
@@ -168,16 +562,37 @@ impl Write for ChannelSender {
 pub struct ChannelCommunicator {
     sender: ChannelSender,
     receiver: BufReader<ChannelReceiver>,
+    framing: Framing,
+    codec: Codec,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
 }
 
 impl ChannelCommunicator {
     pub fn new(rx: Receiver<Vec<u8>>, tx: Sender<Vec<u8>>) -> Self {
+        Self::with_framing(rx, tx, Framing::default())
+    }
+
+    pub fn with_framing(rx: Receiver<Vec<u8>>, tx: Sender<Vec<u8>>, framing: Framing) -> Self {
         Self {
             sender: ChannelSender::new(tx),
             receiver: BufReader::new(ChannelReceiver::new(rx)),
+            framing,
+            codec: Codec::default(),
+            read_timeout: None,
+            write_timeout: None,
         }
     }
 
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    // 容量制限付きのチャネルでのみ意味を持つ
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
     fn mut_cr_inner(&mut self) -> &mut ChannelReceiver {
         self.receiver.get_mut()
     }
@@ -194,29 +609,69 @@ impl CommunicatorCore for ChannelCommunicator {
     fn get_receiver(&mut self) -> &mut BufReader<Self::Receiver> {
         &mut self.receiver
     }
+
+    fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(self.read_timeout)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        ChannelCommunicator::set_read_timeout(self, timeout);
+        Ok(())
+    }
 }
 
 impl Communicator for ChannelCommunicator {
     fn send(&mut self, data: &[u8]) -> Result<()> {
         self.write(data)?;
 
+        let write_timeout = self.write_timeout;
         let sender = &mut self.sender;
-        let mut data: Vec<u8> = sender.send_buf.drain(..).collect();
-        data.extend(b"\n");
-
-        sender
-            .tx
-            .send(data)
-            .map_err(|_| std::io::ErrorKind::BrokenPipe)?;
+        let data: Vec<u8> = sender.send_buf.drain(..).collect();
+
+        match write_timeout {
+            Some(timeout) => sender.tx.send_timeout(data, timeout).map_err(|e| match e {
+                SendTimeoutError::Timeout(_) => normalize_timeout(ErrorKind::TimedOut.into()),
+                SendTimeoutError::Disconnected(_) => ErrorKind::BrokenPipe.into(),
+            })?,
+            None => sender
+                .tx
+                .send(data)
+                .map_err(|_| std::io::ErrorKind::BrokenPipe)?,
+        }
 
         Ok(())
     }
 
     fn receive(&mut self) -> Result<Vec<u8>> {
+        let read_timeout = self.read_timeout;
         let cr = self.mut_cr_inner();
-        let data = cr.rx.recv().map_err(|_| std::io::ErrorKind::BrokenPipe)?;
+        let data = match read_timeout {
+            Some(timeout) => cr.rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => normalize_timeout(ErrorKind::TimedOut.into()),
+                RecvTimeoutError::Disconnected => Error::from(ErrorKind::BrokenPipe),
+            })?,
+            None => cr.rx.recv().map_err(|_| std::io::ErrorKind::BrokenPipe)?,
+        };
         cr.received_buf.extend(data);
 
         self.read()
     }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn set_codec(&mut self, codec: Codec) -> Result<()> {
+        codec.check_framing(self.framing)?;
+        self.codec = codec;
+        Ok(())
+    }
 }
@@ -1,7 +1,19 @@
+#[cfg(feature = "async")]
+pub mod async_comm;
+pub mod beaver;
 pub mod client;
+pub mod codec;
 pub mod comm;
-mod matrix;
+pub mod matrix;
+pub mod mitm;
+pub mod paillier;
+pub mod prg;
+pub mod product;
+pub mod secure;
 pub mod server;
+pub mod sharing;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 #[cfg(test)]
-mod tests;
\ No newline at end of file
+mod tests;
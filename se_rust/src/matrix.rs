@@ -1,51 +1,409 @@
+use crate::codec::Codec;
 use crate::comm::Communicator;
 use anyhow::Result;
+use num_bigint::BigInt;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{Index, IndexMut};
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
-pub struct Matrix {
-    data: Vec<Vec<String>>,
+// 行優先で要素を保持する長方形の行列
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawMatrix<T>")]
+pub struct Matrix<T = f64> {
+    rows: usize,
+    cols: usize,
+    data: Vec<T>,
 }
 
-pub(crate) fn send_table<C: Communicator + ?Sized>(
-    comm: &mut C,
-    table: Vec<Vec<f64>>,
-) -> Result<()> {
-    if table.len() == 0 {
-        return Err(anyhow::anyhow!("Invalid matrix"));
+// デシリアライズ時にも形を検証するための中間表現
+#[derive(Deserialize)]
+struct RawMatrix<T> {
+    rows: usize,
+    cols: usize,
+    data: Vec<T>,
+}
+
+impl<T> TryFrom<RawMatrix<T>> for Matrix<T> {
+    type Error = MatrixError;
+
+    fn try_from(raw: RawMatrix<T>) -> std::result::Result<Self, MatrixError> {
+        Self::new(raw.rows, raw.cols, raw.data)
     }
+}
 
-    let data = table
-        .into_iter()
-        .map(|row| {
-            row.into_iter()
-                .map(|x| format!("{:e}", x))
-                .collect::<Vec<_>>()
-        })
-        .collect::<Vec<_>>();
-    let matrix = Matrix { data };
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatrixError {
+    Empty,
+    Ragged {
+        row: usize,
+        expected: usize,
+        found: usize,
+    },
+    DataLength {
+        rows: usize,
+        cols: usize,
+        len: usize,
+    },
+    // 要素数がusizeに収まらない
+    TooLarge {
+        rows: usize,
+        cols: usize,
+    },
+    TypeMismatch {
+        expected: &'static str,
+        found: String,
+    },
+}
 
-    let data = serde_json::to_string(&matrix)?;
-    comm.send(data.as_bytes())?;
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatrixError::Empty => write!(f, "Matrix must have at least one row and one column"),
+            MatrixError::Ragged {
+                row,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Matrix must be a rectangle: row {} has {} columns but row 0 has {}",
+                row, found, expected
+            ),
+            MatrixError::DataLength { rows, cols, len } => match rows.checked_mul(*cols) {
+                Some(expected) => write!(
+                    f,
+                    "A {}x{} matrix needs {} elements but {} were given",
+                    rows, cols, expected, len
+                ),
+                None => write!(f, "A {}x{} matrix is too large", rows, cols),
+            },
+            MatrixError::TooLarge { rows, cols } => {
+                write!(f, "A {}x{} matrix is too large", rows, cols)
+            }
+            MatrixError::TypeMismatch { expected, found } => write!(
+                f,
+                "Matrix element type mismatch: expected {} but received {}",
+                expected, found
+            ),
+        }
+    }
+}
 
-    Ok(())
+impl std::error::Error for MatrixError {}
+
+// 要素数。行数と列数は相手から受け取ることもあるので、掛け算の桁あふれを確認する
+fn element_count(rows: usize, cols: usize) -> std::result::Result<usize, MatrixError> {
+    if rows == 0 || cols == 0 {
+        return Err(MatrixError::Empty);
+    }
+
+    rows.checked_mul(cols)
+        .ok_or(MatrixError::TooLarge { rows, cols })
 }
 
-pub(crate) fn receive_table<C: Communicator + ?Sized>(comm: &mut C) -> Result<Vec<Vec<f64>>> {
-    let data = comm.receive()?;
-    let matrix = serde_json::from_slice::<Matrix>(&data)?;
+impl<T> Matrix<T> {
+    // 行優先の平坦なバッファから作る
+    pub fn new(rows: usize, cols: usize, data: Vec<T>) -> std::result::Result<Self, MatrixError> {
+        if data.len() != element_count(rows, cols)? {
+            return Err(MatrixError::DataLength {
+                rows,
+                cols,
+                len: data.len(),
+            });
+        }
+
+        Ok(Self { rows, cols, data })
+    }
+
+    pub fn from_rows(table: Vec<Vec<T>>) -> std::result::Result<Self, MatrixError> {
+        let rows = table.len();
+        let cols = table.first().map(|r| r.len()).unwrap_or(0);
+        let len = element_count(rows, cols)?;
+        // 確保する前に形を確認する
+        if let Some((i, row)) = table.iter().enumerate().find(|(_, r)| r.len() != cols) {
+            return Err(MatrixError::Ragged {
+                row: i,
+                expected: cols,
+                found: row.len(),
+            });
+        }
+
+        let mut data = Vec::with_capacity(len);
+        for row in table {
+            data.extend(row);
+        }
 
+        Ok(Self { rows, cols, data })
+    }
+
+    pub fn from_fn<F>(rows: usize, cols: usize, mut f: F) -> std::result::Result<Self, MatrixError>
+    where
+        F: FnMut(usize, usize) -> T,
+    {
+        let len = element_count(rows, cols)?;
+        let data = (0..len).map(|k| f(k / cols, k % cols)).collect();
+
+        Self::new(rows, cols, data)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    pub fn get(&self, row: usize, col: usize) -> Option<&T> {
+        if row < self.rows && col < self.cols {
+            self.data.get(row * self.cols + col)
+        } else {
+            None
+        }
+    }
+
+    pub fn row(&self, row: usize) -> &[T] {
+        &self.data[row * self.cols..(row + 1) * self.cols]
+    }
+
+    pub fn iter_rows(&self) -> impl Iterator<Item = &[T]> {
+        self.data.chunks(self.cols)
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.data
+    }
+
+    pub fn into_rows(self) -> Vec<Vec<T>> {
+        let cols = self.cols;
+        let mut data = self.data.into_iter();
+
+        (0..self.rows)
+            .map(|_| data.by_ref().take(cols).collect())
+            .collect()
+    }
+}
+
+impl<T: Clone> Matrix<T> {
+    pub fn to_rows(&self) -> Vec<Vec<T>> {
+        self.iter_rows().map(|row| row.to_vec()).collect()
+    }
+}
+
+impl<T> Index<(usize, usize)> for Matrix<T> {
+    type Output = T;
+
+    fn index(&self, (row, col): (usize, usize)) -> &T {
+        assert!(
+            row < self.rows && col < self.cols,
+            "index ({}, {}) out of range for {}x{} matrix",
+            row,
+            col,
+            self.rows,
+            self.cols
+        );
+        &self.data[row * self.cols + col]
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Matrix<T> {
+    fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut T {
+        assert!(
+            row < self.rows && col < self.cols,
+            "index ({}, {}) out of range for {}x{} matrix",
+            row,
+            col,
+            self.rows,
+            self.cols
+        );
+        &mut self.data[row * self.cols + col]
+    }
+}
+
+impl<T> TryFrom<Vec<Vec<T>>> for Matrix<T> {
+    type Error = MatrixError;
+
+    fn try_from(table: Vec<Vec<T>>) -> std::result::Result<Self, MatrixError> {
+        Self::from_rows(table)
+    }
+}
+
+impl<T> From<Matrix<T>> for Vec<Vec<T>> {
+    fn from(matrix: Matrix<T>) -> Self {
+        matrix.into_rows()
+    }
+}
+
+// 表で送受信できる要素の型
+// 型ごとのタグを一緒に送るので、受信側で型を取り違えた場合はエラーになる
+pub trait Element: Clone + Serialize + DeserializeOwned + Send + Sync + 'static {
+    const TYPE_TAG: &'static str;
+
+    fn to_wire_string(&self) -> String;
+    fn from_wire_string(s: &str) -> Option<Self>;
+}
+
+impl Element for f64 {
+    const TYPE_TAG: &'static str = "f64";
+
+    fn to_wire_string(&self) -> String {
+        format!("{:e}", self)
+    }
+
+    fn from_wire_string(s: &str) -> Option<Self> {
+        s.parse().ok()
+    }
+}
+
+macro_rules! impl_integer_element {
+    ($($t:ty => $tag:expr),*) => {
+        $(
+            impl Element for $t {
+                const TYPE_TAG: &'static str = $tag;
+
+                fn to_wire_string(&self) -> String {
+                    self.to_string()
+                }
+
+                fn from_wire_string(s: &str) -> Option<Self> {
+                    s.parse().ok()
+                }
+            }
+        )*
+    };
+}
+
+impl_integer_element!(i64 => "i64", u64 => "u64", i128 => "i128", BigInt => "bigint");
+
+// 通信路上の表現。Go版と同じく数値は文字列で送る
+// Go版との互換のため、f64の場合は型タグを付けない
+#[derive(Serialize, Deserialize, Debug)]
+struct TableMessage {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    type_tag: Option<String>,
+    data: Vec<Vec<String>>,
+}
+
+fn check_type_tag<T: Element>(found: &str) -> Result<()> {
+    if found != T::TYPE_TAG {
+        return Err(MatrixError::TypeMismatch {
+            expected: T::TYPE_TAG,
+            found: found.to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+fn encode_json_matrix<T: Element>(matrix: &Matrix<T>) -> Result<Vec<u8>> {
     let data = matrix
+        .iter_rows()
+        .map(|row| row.iter().map(T::to_wire_string).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let type_tag = (T::TYPE_TAG != f64::TYPE_TAG).then(|| T::TYPE_TAG.to_string());
+    let message = TableMessage { type_tag, data };
+
+    Ok(serde_json::to_vec(&message)?)
+}
+
+fn decode_json_matrix<T: Element>(data: &[u8]) -> Result<Matrix<T>> {
+    let message = serde_json::from_slice::<TableMessage>(data)?;
+    check_type_tag::<T>(message.type_tag.as_deref().unwrap_or(f64::TYPE_TAG))?;
+
+    let data = message
         .data
         .iter()
         .map(|row| {
             row.iter()
                 .map(|x| {
-                    x.parse::<f64>()
-                        .map_err(|_| anyhow::anyhow!("Invalid number"))
+                    T::from_wire_string(x).ok_or_else(|| anyhow::anyhow!("Invalid number: {:?}", x))
                 })
                 .collect::<Result<Vec<_>>>()
         })
         .collect::<Result<Vec<_>>>()?;
 
-    Ok(data)
+    Ok(Matrix::from_rows(data)?)
+}
+
+// バイナリ形式では(型タグ, 行列)の組で送り、先に型タグだけを読んで確認する
+fn decode_binary_type_tag(codec: Codec, data: &[u8]) -> Result<String> {
+    match codec {
+        // bincodeは自己記述的でないので先頭の文字列だけを読む
+        Codec::Bincode => Ok(bincode::deserialize::<String>(data)?),
+        codec => Ok(codec.decode::<(String, IgnoredAny)>(data)?.0),
+    }
+}
+
+// JSONでは従来通りの形式、それ以外のコーデックでは数値をそのまま送る
+pub(crate) fn encode_matrix<T: Element>(codec: Codec, matrix: &Matrix<T>) -> Result<Vec<u8>> {
+    match codec {
+        Codec::Json => encode_json_matrix(matrix),
+        codec => codec.encode(&(T::TYPE_TAG, matrix)),
+    }
+}
+
+pub(crate) fn decode_matrix<T: Element>(codec: Codec, data: &[u8]) -> Result<Matrix<T>> {
+    match codec {
+        Codec::Json => decode_json_matrix(data),
+        codec => {
+            check_type_tag::<T>(&decode_binary_type_tag(codec, data)?)?;
+            let (_, matrix) = codec.decode::<(String, Matrix<T>)>(data)?;
+
+            Ok(matrix)
+        }
+    }
+}
+
+pub(crate) fn encode_table(codec: Codec, table: Vec<Vec<f64>>) -> Result<Vec<u8>> {
+    encode_matrix(codec, &Matrix::from_rows(table)?)
+}
+
+pub(crate) fn decode_table(codec: Codec, data: &[u8]) -> Result<Vec<Vec<f64>>> {
+    Ok(decode_matrix::<f64>(codec, data)?.into_rows())
+}
+
+pub(crate) fn send_table<C: Communicator + ?Sized>(
+    comm: &mut C,
+    table: Vec<Vec<f64>>,
+) -> Result<()> {
+    let data = encode_table(comm.codec(), table)?;
+    comm.send(&data)?;
+
+    Ok(())
+}
+
+pub(crate) fn receive_table<C: Communicator + ?Sized>(comm: &mut C) -> Result<Vec<Vec<f64>>> {
+    let data = comm.receive()?;
+
+    decode_table(comm.codec(), &data)
+}
+
+pub(crate) fn send_matrix<C, T>(comm: &mut C, matrix: &Matrix<T>) -> Result<()>
+where
+    C: Communicator + ?Sized,
+    T: Element,
+{
+    let data = encode_matrix(comm.codec(), matrix)?;
+    comm.send(&data)?;
+
+    Ok(())
+}
+
+pub(crate) fn receive_matrix<C, T>(comm: &mut C) -> Result<Matrix<T>>
+where
+    C: Communicator + ?Sized,
+    T: Element,
+{
+    let data = comm.receive()?;
+
+    decode_matrix(comm.codec(), &data)
 }
@@ -0,0 +1,316 @@
+use crate::client::TcpClient;
+use crate::codec::Codec;
+use crate::comm::{Communicator, CommunicatorCore, TcpCommunicator};
+use crate::matrix;
+use crate::server::TcpServer;
+use anyhow::Result;
+use std::io::BufRead;
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+
+// 中間者攻撃のデモ用の中継
+// 中学側と予備校側の間に入り、流れるメッセージを表示しながら規則に従って書き換える
+// 中継は固定のフレーミングとコーデックで読むので、フレーミングやコーデックのネゴシエーションを行う相手には使えない
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // 中学側から予備校側へ
+    ClientToServer,
+    // 予備校側から中学側へ
+    ServerToClient,
+}
+
+impl Direction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::ClientToServer => "c2s",
+            Direction::ServerToClient => "s2c",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Direction> {
+        [Direction::ClientToServer, Direction::ServerToClient]
+            .into_iter()
+            .find(|d| d.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    // メッセージを捨てる
+    Drop,
+    // 表の要素を書き換える
+    Set { row: usize, col: usize, value: f64 },
+    // 表の要素に値を足す
+    Add { row: usize, col: usize, value: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub direction: Direction,
+    // 方向ごとに1から数えたメッセージの番号。Noneなら全てのメッセージ
+    pub index: Option<usize>,
+    pub action: Action,
+}
+
+impl Rule {
+    fn matches(&self, direction: Direction, index: usize) -> bool {
+        self.direction == direction && self.index.is_none_or(|i| i == index)
+    }
+}
+
+// 規則ファイルは1行に1つの規則を書く。`#`以降はコメント
+//
+//   drop <方向> <番号>
+//   set <方向> <番号> <行> <列> <値>
+//   add <方向> <番号> <行> <列> <値>
+//
+// 方向は`c2s`か`s2c`、番号は`*`にすると全てのメッセージが対象になる
+// 行と列は0から数える
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self(rules)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let rule = parse_rule(line).map_err(|e| anyhow::anyhow!("Line {}: {}", i + 1, e))?;
+            rules.push(rule);
+        }
+
+        Ok(Self(rules))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.0
+    }
+
+    // 書き換え後のメッセージを返す。捨てる場合はNone
+    pub fn apply(
+        &self,
+        direction: Direction,
+        index: usize,
+        codec: Codec,
+        data: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        let rules = self
+            .0
+            .iter()
+            .filter(|rule| rule.matches(direction, index))
+            .collect::<Vec<_>>();
+        if rules.is_empty() {
+            return Ok(Some(data));
+        }
+        if rules.iter().any(|rule| rule.action == Action::Drop) {
+            return Ok(None);
+        }
+
+        // 表として読めないメッセージは書き換えずに通す
+        let Ok(mut table) = matrix::decode_matrix::<f64>(codec, &data) else {
+            return Ok(Some(data));
+        };
+        for rule in rules {
+            let (row, col, value, add) = match rule.action {
+                Action::Set { row, col, value } => (row, col, value, false),
+                Action::Add { row, col, value } => (row, col, value, true),
+                Action::Drop => unreachable!(),
+            };
+            if row >= table.rows() || col >= table.cols() {
+                return Err(anyhow::anyhow!(
+                    "Rule {:?} is out of range for a {}x{} table",
+                    rule,
+                    table.rows(),
+                    table.cols()
+                ));
+            }
+            if add {
+                table[(row, col)] += value;
+            } else {
+                table[(row, col)] = value;
+            }
+        }
+
+        Ok(Some(matrix::encode_matrix(codec, &table)?))
+    }
+}
+
+fn parse_rule(line: &str) -> Result<Rule> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let (&action, args) = words.split_first().unwrap();
+    let expected = match action {
+        "drop" => 2,
+        "set" | "add" => 5,
+        _ => return Err(anyhow::anyhow!("Unknown action {:?}", action)),
+    };
+    if args.len() != expected {
+        return Err(anyhow::anyhow!(
+            "{:?} takes {} arguments but {} were given",
+            action,
+            expected,
+            args.len()
+        ));
+    }
+
+    let direction = Direction::from_name(args[0])
+        .ok_or_else(|| anyhow::anyhow!("Unknown direction {:?}", args[0]))?;
+    let index = match args[1] {
+        "*" => None,
+        index => match index.parse()? {
+            0 => return Err(anyhow::anyhow!("Message numbers start from 1")),
+            index => Some(index),
+        },
+    };
+    let action = match action {
+        "drop" => Action::Drop,
+        action => {
+            let (row, col, value) = (args[2].parse()?, args[3].parse()?, args[4].parse()?);
+            if action == "set" {
+                Action::Set { row, col, value }
+            } else {
+                Action::Add { row, col, value }
+            }
+        }
+    };
+
+    Ok(Rule {
+        direction,
+        index,
+        action,
+    })
+}
+
+// ログ用にメッセージを読める形にする
+pub fn describe(codec: Codec, data: &[u8]) -> String {
+    if let Ok(table) = matrix::decode_matrix::<f64>(codec, data) {
+        let (rows, cols) = table.shape();
+        return format!("table {}x{} {:?}", rows, cols, table.into_rows());
+    }
+    if let Ok(text) = std::str::from_utf8(data) {
+        return format!("message {:?}", text);
+    }
+    // bincode以外は自己記述的なので中身を表示できる
+    if codec != Codec::Bincode {
+        if let Ok(value) = codec.decode::<serde_json::Value>(data) {
+            return format!("value {}", value);
+        }
+    }
+
+    let hex = data
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("binary ({} bytes) {}", data.len(), hex)
+}
+
+// 片方向の中継。送信元が接続を閉じるまで続ける
+// 終わったら両方の接続を閉じ、逆方向の中継も終わらせる
+pub fn relay(
+    from: &mut TcpCommunicator,
+    to: &mut TcpCommunicator,
+    direction: Direction,
+    rules: &Rules,
+) -> Result<()> {
+    let result = forward(from, to, direction, rules);
+
+    // 逆方向の中継が先に閉じている場合のエラーは無視する
+    let _ = from.sender.shutdown(Shutdown::Both);
+    let _ = to.sender.shutdown(Shutdown::Both);
+
+    result
+}
+
+fn forward(
+    from: &mut TcpCommunicator,
+    to: &mut TcpCommunicator,
+    direction: Direction,
+    rules: &Rules,
+) -> Result<()> {
+    for index in 1.. {
+        // Lineフレーミングでは空のメッセージと切断が区別できないので、先に確認する
+        if from.get_receiver().fill_buf()?.is_empty() {
+            println!("[{}] connection closed", direction.name());
+            break;
+        }
+
+        let data = from.receive()?;
+        println!(
+            "[{} #{}] {}",
+            direction.name(),
+            index,
+            describe(from.codec(), &data)
+        );
+
+        // 適用できない規則があっても中継は止めず、そのまま送る
+        let forwarded = match rules.apply(direction, index, from.codec(), data.clone()) {
+            Ok(forwarded) => forwarded,
+            Err(e) => {
+                println!("[{} #{}] rule not applied: {}", direction.name(), index, e);
+                Some(data.clone())
+            }
+        };
+        match forwarded {
+            None => println!("[{} #{}] dropped", direction.name(), index),
+            Some(forwarded) => {
+                if forwarded != data {
+                    println!(
+                        "[{} #{}] rewritten to {}",
+                        direction.name(),
+                        index,
+                        describe(to.codec(), &forwarded)
+                    );
+                }
+                to.send(&forwarded)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn duplicate(comm: &TcpCommunicator) -> Result<TcpCommunicator> {
+    let mut duplicated = TcpCommunicator::new(comm.sender.try_clone()?, comm.framing())?;
+    duplicated.set_codec(comm.codec())?;
+
+    Ok(duplicated)
+}
+
+// 中学側(`client`)と予備校側(`server`)の間で双方向に中継する
+pub fn run(mut client: TcpServer, mut server: TcpClient, rules: Rules) -> Result<()> {
+    let mut to_client = duplicate(client.get_ref())?;
+    let mut to_server = duplicate(server.get_ref())?;
+    let rules = Arc::new(rules);
+
+    let r = Arc::clone(&rules);
+    let upstream = thread::spawn(move || {
+        relay(
+            client.get_mut(),
+            &mut to_server,
+            Direction::ClientToServer,
+            &r,
+        )
+    });
+    let downstream = relay(
+        server.get_mut(),
+        &mut to_client,
+        Direction::ServerToClient,
+        &rules,
+    );
+
+    upstream.join().unwrap()?;
+    downstream
+}
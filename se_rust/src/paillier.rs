@@ -0,0 +1,304 @@
+use crate::comm::Communicator;
+use crate::matrix::Matrix;
+use anyhow::Result;
+use num_bigint::{BigInt, BigUint, RandBigInt, Sign};
+use num_integer::Integer;
+use num_traits::{CheckedSub, One, Zero};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// Paillier暗号(加法準同型暗号)
+// 平文は法nの整数で、n/2より大きい値は負の数として扱う
+
+// 実験で使う鍵長。テストなどではより短い鍵も使える
+pub const DEFAULT_KEY_BITS: u64 = 2048;
+
+// これより短い鍵は安全でない上に平文がほとんど入らないので作らない
+const MIN_KEY_BITS: u64 = 128;
+
+// Miller-Rabin判定の繰り返し回数
+const PRIME_TEST_ROUNDS: usize = 40;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicKey {
+    n: BigUint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrivateKey {
+    public_key: PublicKey,
+    lambda: BigUint,
+    mu: BigUint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ciphertext(BigUint);
+
+pub fn generate_keypair<R: Rng + ?Sized>(
+    bits: u64,
+    rng: &mut R,
+) -> Result<(PublicKey, PrivateKey)> {
+    if bits < MIN_KEY_BITS {
+        return Err(anyhow::anyhow!(
+            "Key must be at least {} bits but {} was given",
+            MIN_KEY_BITS,
+            bits
+        ));
+    }
+
+    loop {
+        let p = generate_prime(bits / 2, rng);
+        let q = generate_prime(bits - bits / 2, rng);
+        if p == q {
+            continue;
+        }
+
+        let n = &p * &q;
+        let lambda = (&p - 1u32).lcm(&(&q - 1u32));
+        // g = n + 1 なので mu = lambda^-1 mod n
+        let Some(mu) = lambda.modinv(&n) else {
+            continue;
+        };
+
+        let public_key = PublicKey { n };
+        let private_key = PrivateKey {
+            public_key: public_key.clone(),
+            lambda,
+            mu,
+        };
+
+        return Ok((public_key, private_key));
+    }
+}
+
+impl PublicKey {
+    pub fn n(&self) -> &BigUint {
+        &self.n
+    }
+
+    fn n_squared(&self) -> BigUint {
+        &self.n * &self.n
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.n.bits()
+    }
+
+    // 平文を[0, n)に埋め込む
+    fn encode(&self, m: &BigInt) -> Result<BigUint> {
+        let half = BigInt::from_biguint(Sign::Plus, &self.n >> 1);
+        if m.magnitude() > half.magnitude() {
+            return Err(anyhow::anyhow!(
+                "Plaintext does not fit in a {}-bit key",
+                self.bits()
+            ));
+        }
+
+        Ok(self.reduce(m))
+    }
+
+    fn reduce(&self, m: &BigInt) -> BigUint {
+        let n = BigInt::from_biguint(Sign::Plus, self.n.clone());
+        m.mod_floor(&n).to_biguint().unwrap()
+    }
+
+    fn decode(&self, m: BigUint) -> BigInt {
+        if m > &self.n >> 1 {
+            -BigInt::from_biguint(Sign::Plus, &self.n - m)
+        } else {
+            BigInt::from_biguint(Sign::Plus, m)
+        }
+    }
+
+    // 受信した暗号文が[1, n^2)に入っていて、nと互いに素であることを確認する
+    // nと共通の因数を持つ値は暗号化では作られず、復号もできない
+    pub fn check(&self, c: &Ciphertext) -> Result<()> {
+        if c.0.is_zero() || c.0 >= self.n_squared() {
+            return Err(anyhow::anyhow!(
+                "Ciphertext is out of range for a {}-bit key",
+                self.bits()
+            ));
+        }
+        if !c.0.gcd(&self.n).is_one() {
+            return Err(anyhow::anyhow!("Ciphertext is not coprime to the modulus"));
+        }
+
+        Ok(())
+    }
+
+    pub fn encrypt<R: Rng + ?Sized>(&self, m: &BigInt, rng: &mut R) -> Result<Ciphertext> {
+        let m = self.encode(m)?;
+        let n_squared = self.n_squared();
+
+        let r = loop {
+            let r = rng.gen_biguint_range(&BigUint::one(), &self.n);
+            if r.gcd(&self.n).is_one() {
+                break r;
+            }
+        };
+
+        // (1 + n)^m = 1 + mn (mod n^2)
+        let gm = (BigUint::one() + m * &self.n) % &n_squared;
+
+        Ok(Ciphertext(gm * r.modpow(&self.n, &n_squared) % n_squared))
+    }
+
+    pub fn encrypt_vector<T, R>(&self, plain: &[T], rng: &mut R) -> Result<Vec<Ciphertext>>
+    where
+        T: Clone + Into<BigInt>,
+        R: Rng + ?Sized,
+    {
+        plain
+            .iter()
+            .map(|m| self.encrypt(&m.clone().into(), rng))
+            .collect()
+    }
+
+    // Enc(a) * Enc(b) = Enc(a + b)
+    pub fn add(&self, a: &Ciphertext, b: &Ciphertext) -> Ciphertext {
+        Ciphertext(&a.0 * &b.0 % self.n_squared())
+    }
+
+    pub fn add_plain(&self, a: &Ciphertext, m: &BigInt) -> Result<Ciphertext> {
+        let n_squared = self.n_squared();
+        let gm = (BigUint::one() + self.encode(m)? * &self.n) % &n_squared;
+
+        Ok(Ciphertext(&a.0 * gm % n_squared))
+    }
+
+    // Enc(a)^k = Enc(ka)
+    pub fn mul_plain(&self, a: &Ciphertext, k: &BigInt) -> Ciphertext {
+        Ciphertext(a.0.modpow(&self.reduce(k), &self.n_squared()))
+    }
+
+    // 暗号化されたベクトルと平文のベクトルの内積
+    pub fn dot<T: Clone + Into<BigInt>>(
+        &self,
+        encrypted: &[Ciphertext],
+        plain: &[T],
+    ) -> Result<Ciphertext> {
+        if encrypted.len() != plain.len() {
+            return Err(anyhow::anyhow!(
+                "Vector lengths do not match: {} and {}",
+                encrypted.len(),
+                plain.len()
+            ));
+        }
+
+        Ok(encrypted
+            .iter()
+            .zip(plain)
+            .map(|(c, k)| self.mul_plain(c, &k.clone().into()))
+            .fold(Ciphertext(BigUint::one()), |acc, c| self.add(&acc, &c)))
+    }
+
+    // 平文の行列と暗号化されたベクトルの積
+    pub fn matrix_vector_product<T: Clone + Into<BigInt>>(
+        &self,
+        matrix: &Matrix<T>,
+        encrypted: &[Ciphertext],
+    ) -> Result<Vec<Ciphertext>> {
+        matrix
+            .iter_rows()
+            .map(|row| self.dot(encrypted, row))
+            .collect()
+    }
+}
+
+impl PrivateKey {
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn decrypt(&self, c: &Ciphertext) -> Result<BigInt> {
+        let public_key = &self.public_key;
+        public_key.check(c)?;
+
+        // L(c^lambda mod n^2) * mu mod n
+        let x = c.0.modpow(&self.lambda, &public_key.n_squared());
+        let x = x
+            .checked_sub(&BigUint::one())
+            .ok_or_else(|| anyhow::anyhow!("Ciphertext cannot be decrypted"))?;
+        let m = x / &public_key.n * &self.mu % &public_key.n;
+
+        Ok(public_key.decode(m))
+    }
+
+    pub fn decrypt_vector(&self, ciphertexts: &[Ciphertext]) -> Result<Vec<BigInt>> {
+        ciphertexts.iter().map(|c| self.decrypt(c)).collect()
+    }
+}
+
+fn generate_prime<R: Rng + ?Sized>(bits: u64, rng: &mut R) -> BigUint {
+    loop {
+        // 最上位ビットと最下位ビットを立てて、bitsビットの奇数にする
+        let mut candidate = rng.gen_biguint(bits);
+        candidate.set_bit(bits - 1, true);
+        candidate.set_bit(0, true);
+        if is_probable_prime(&candidate, rng) {
+            return candidate;
+        }
+    }
+}
+
+fn is_probable_prime<R: Rng + ?Sized>(n: &BigUint, rng: &mut R) -> bool {
+    const SMALL_PRIMES: [u32; 15] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47];
+
+    if n < &BigUint::from(2u32) {
+        return false;
+    }
+    for p in SMALL_PRIMES {
+        if (n % p).is_zero() {
+            return n == &BigUint::from(p);
+        }
+    }
+
+    let n_minus_one = n - 1u32;
+    let s = n_minus_one.trailing_zeros().unwrap();
+    let d = &n_minus_one >> s;
+    let two = BigUint::from(2u32);
+
+    (0..PRIME_TEST_ROUNDS).all(|_| {
+        let a = rng.gen_biguint_range(&two, &n_minus_one);
+        let mut x = a.modpow(&d, n);
+        if x.is_one() || x == n_minus_one {
+            return true;
+        }
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_minus_one {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+pub fn send_public_key<C: Communicator>(comm: &mut C, key: &PublicKey) -> Result<()> {
+    comm.send_value(key)
+}
+
+pub fn receive_public_key<C: Communicator>(comm: &mut C) -> Result<PublicKey> {
+    let key: PublicKey = comm.receive_value()?;
+    if key.bits() < MIN_KEY_BITS || key.n.is_even() {
+        return Err(anyhow::anyhow!("Received an invalid public key"));
+    }
+
+    Ok(key)
+}
+
+pub fn send_ciphertexts<C: Communicator>(comm: &mut C, ciphertexts: &[Ciphertext]) -> Result<()> {
+    comm.send_value(ciphertexts)
+}
+
+pub fn receive_ciphertexts<C: Communicator>(
+    comm: &mut C,
+    key: &PublicKey,
+) -> Result<Vec<Ciphertext>> {
+    let ciphertexts: Vec<Ciphertext> = comm.receive_value()?;
+    for c in &ciphertexts {
+        key.check(c)?;
+    }
+
+    Ok(ciphertexts)
+}
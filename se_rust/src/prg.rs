@@ -0,0 +1,105 @@
+use crate::comm::Communicator;
+use crate::matrix::Matrix;
+use crate::sharing::Field;
+use anyhow::Result;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+// シードから決定的に乱数を生成する暗号論的擬似乱数生成器
+// 同じシードからは同じ乱数列が得られるので、失敗した実行を再現できる
+
+pub const SEED_LEN: usize = 32;
+
+pub type Seed = [u8; SEED_LEN];
+
+pub fn random_seed() -> Seed {
+    let mut seed = [0; SEED_LEN];
+    OsRng.fill_bytes(&mut seed);
+
+    seed
+}
+
+#[derive(Debug, Clone)]
+pub struct Prg(ChaCha20Rng);
+
+impl Prg {
+    pub fn from_seed(seed: Seed) -> Self {
+        Self(ChaCha20Rng::from_seed(seed))
+    }
+
+    // 同じシードから用途ごとに独立した乱数列を作る
+    pub fn with_stream(seed: Seed, stream: u64) -> Self {
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        rng.set_stream(stream);
+
+        Self(rng)
+    }
+
+    pub fn from_entropy() -> Self {
+        Self::from_seed(random_seed())
+    }
+
+    pub fn seed(&self) -> Seed {
+        self.0.get_seed()
+    }
+
+    // [-range, range) の一様乱数からなる行列
+    pub fn random_matrix(&mut self, rows: usize, cols: usize, range: f64) -> Result<Matrix> {
+        Ok(Matrix::from_fn(rows, cols, |_, _| {
+            self.0.gen_range(-range..range)
+        })?)
+    }
+
+    pub fn random_field_matrix(
+        &mut self,
+        field: Field,
+        rows: usize,
+        cols: usize,
+    ) -> Result<Matrix<u64>> {
+        field.random_matrix(rows, cols, &mut self.0)
+    }
+}
+
+impl RngCore for Prg {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand::Error> {
+        self.0.try_fill_bytes(dest)
+    }
+}
+
+impl CryptoRng for Prg {}
+
+pub fn send_seed<C: Communicator>(comm: &mut C, seed: &Seed) -> Result<()> {
+    comm.send_value(seed)
+}
+
+pub fn receive_seed<C: Communicator>(comm: &mut C) -> Result<Seed> {
+    comm.receive_value()
+}
+
+// 双方が乱数を出し合い、そのXORを共通のシードにする
+// 両者が呼ぶ必要がある
+pub fn exchange_seed<C: Communicator>(comm: &mut C) -> Result<Seed> {
+    let mine = random_seed();
+    send_seed(comm, &mine)?;
+    let theirs = receive_seed(comm)?;
+
+    let mut seed = [0; SEED_LEN];
+    for (s, (a, b)) in seed.iter_mut().zip(mine.iter().zip(&theirs)) {
+        *s = a ^ b;
+    }
+
+    Ok(seed)
+}
@@ -0,0 +1,452 @@
+use crate::comm::Communicator;
+use crate::matrix::Matrix;
+use crate::prg::{self, Prg};
+use anyhow::Result;
+use rand::Rng;
+
+// 2者間の秘密行列積
+// 予備校側(Server)が行列A、中学側(Client)が行列Bを持ち、
+// 互いに入力を明かさずにABの加法的なシェアを得る
+// 実数で計算するので、結果には丸め誤差が含まれる
+
+// マスクに使う乱数の範囲。大きいほど入力をよく隠せるが、誤差も大きくなる
+const MASK_RANGE: f64 = 1000.0;
+
+// ランダムな可逆行列を作り直す回数の上限
+const MAX_INVERT_ATTEMPTS: usize = 16;
+
+// 逆行列がほぼ単位行列に戻らない行列は条件数が悪いとみなす
+const INVERT_TOLERANCE: f64 = 1e-9;
+
+// ディーラーが受け取る形の上限。各行列(積n x mも含む)の要素数と、積の計算量n*k*mを抑える
+const MAX_DIMENSION: usize = 1 << 12;
+const MAX_ELEMENTS: usize = 1 << 20;
+const MAX_OPERATIONS: usize = 1 << 28;
+
+// シードを使うディーラーで、予備校側のRaとraを生成する乱数列の番号
+const RA_STREAM: u64 = 0;
+const SA_STREAM: u64 = 1;
+
+fn send<C: Communicator>(comm: &mut C, matrix: &Matrix) -> Result<()> {
+    comm.send_table(matrix.to_rows())
+}
+
+fn receive<C: Communicator>(comm: &mut C) -> Result<Matrix> {
+    Ok(Matrix::from_rows(comm.receive_table()?)?)
+}
+
+fn check_shape(name: &str, matrix: &Matrix, expected: (usize, usize)) -> Result<()> {
+    if matrix.shape() != expected {
+        return Err(anyhow::anyhow!(
+            "Expected {} to be a {:?} matrix but received {:?}",
+            name,
+            expected,
+            matrix.shape()
+        ));
+    }
+
+    Ok(())
+}
+
+// 予備校側の処理。返り値は積ABの予備校側のシェア
+// 内側の次元が2以上である必要がある
+pub fn server_multiply<C, R>(comm: &mut C, a: &Matrix, rng: &mut R) -> Result<Matrix>
+where
+    C: Communicator,
+    R: Rng + ?Sized,
+{
+    let k = a.cols();
+    if k < 2 {
+        return Err(anyhow::anyhow!(
+            "Inner dimension must be at least 2 but was {}",
+            k
+        ));
+    }
+
+    // AM = [A_l | A_r] のうち左半分を中学側に渡す
+    let m = random_invertible(k, rng)?;
+    send(comm, &m)?;
+    let (a_l, a_r) = split_cols(&mul(a, &m)?, k / 2)?;
+    send(comm, &a_l)?;
+
+    // M^-1 B = [B_t; B_b] のうち下半分を受け取る
+    let b_b = receive(comm)?;
+    if b_b.rows() != k - k / 2 {
+        return Err(anyhow::anyhow!(
+            "Expected {} rows from the client but received {:?}",
+            k - k / 2,
+            b_b.shape()
+        ));
+    }
+
+    mul(&a_r, &b_b)
+}
+
+// 中学側の処理。返り値は積ABの中学側のシェア
+pub fn client_multiply<C: Communicator>(comm: &mut C, b: &Matrix) -> Result<Matrix> {
+    let k = b.rows();
+    let m = receive(comm)?;
+    check_shape("the random matrix", &m, (k, k))?;
+    let m_inv = invert(&m).ok_or_else(|| anyhow::anyhow!("Received a singular matrix"))?;
+    let (b_t, b_b) = split_rows(&mul(&m_inv, b)?, k / 2)?;
+
+    let a_l = receive(comm)?;
+    if a_l.cols() != k / 2 {
+        return Err(anyhow::anyhow!(
+            "Expected {} columns from the server but received {:?}",
+            k / 2,
+            a_l.shape()
+        ));
+    }
+    send(comm, &b_b)?;
+
+    mul(&a_l, &b_t)
+}
+
+// 以下は信頼できる第三者(ディーラー)が乱数を配る方式
+// ディーラーは Ra, Rb と ra + rb = Ra Rb を満たす ra, rb を作り、
+// 予備校側に(Ra, ra)、中学側に(Rb, rb)を渡す
+
+fn send_shape<C: Communicator>(comm: &mut C, shape: (usize, usize)) -> Result<()> {
+    comm.send_table(vec![vec![shape.0 as f64, shape.1 as f64]])
+}
+
+fn receive_shape<C: Communicator>(comm: &mut C) -> Result<(usize, usize)> {
+    let shape = receive(comm)?;
+    let dimension = |x: f64| {
+        ((1.0..=MAX_DIMENSION as f64).contains(&x) && x.fract() == 0.0).then_some(x as usize)
+    };
+    let (rows, cols) = match shape.as_slice() {
+        &[rows, cols] => (dimension(rows), dimension(cols)),
+        _ => (None, None),
+    };
+
+    // 大きすぎる形で確保しないよう、要素数も確認する
+    match (rows, cols) {
+        (Some(rows), Some(cols)) if rows.checked_mul(cols).is_some_and(|n| n <= MAX_ELEMENTS) => {
+            Ok((rows, cols))
+        }
+        _ => Err(anyhow::anyhow!("Invalid matrix shape: {:?}", shape)),
+    }
+}
+
+fn receive_shapes<S, C>(server: &mut S, client: &mut C) -> Result<(usize, usize, usize)>
+where
+    S: Communicator,
+    C: Communicator,
+{
+    let (n, k) = receive_shape(server)?;
+    let (k2, m) = receive_shape(client)?;
+    if k != k2 {
+        return Err(anyhow::anyhow!(
+            "Cannot multiply a {:?} matrix by a {:?} matrix",
+            (n, k),
+            (k2, m)
+        ));
+    }
+
+    // 2つの形がそれぞれ上限内でも、積の要素数や計算量は大きくなりうる
+    let elements = n.checked_mul(m).filter(|&x| x <= MAX_ELEMENTS);
+    let operations = elements
+        .and_then(|x| x.checked_mul(k))
+        .filter(|&x| x <= MAX_OPERATIONS);
+    if operations.is_none() {
+        return Err(anyhow::anyhow!(
+            "Product of a {:?} matrix and a {:?} matrix is too large",
+            (n, k),
+            (k2, m)
+        ));
+    }
+
+    Ok((n, k, m))
+}
+
+// ディーラーの処理。予備校側と中学側それぞれとの通信路を受け取る
+pub fn deal<S, C, R>(server: &mut S, client: &mut C, rng: &mut R) -> Result<()>
+where
+    S: Communicator,
+    C: Communicator,
+    R: Rng + ?Sized,
+{
+    let (n, k, m) = receive_shapes(server, client)?;
+
+    let r_a = random_matrix(n, k, rng)?;
+    let r_b = random_matrix(k, m, rng)?;
+    let s_a = random_matrix(n, m, rng)?;
+    let s_b = sub(&mul(&r_a, &r_b)?, &s_a)?;
+
+    send(server, &r_a)?;
+    send(server, &s_a)?;
+    send(client, &r_b)?;
+    send(client, &s_b)?;
+
+    Ok(())
+}
+
+// シードを使うディーラーの処理
+// Ra, ra, Rbは各自がシードから生成するので、行列として送るのはrbだけになる
+pub fn deal_seeded<S, C>(server: &mut S, client: &mut C) -> Result<()>
+where
+    S: Communicator,
+    C: Communicator,
+{
+    let (n, k, m) = receive_shapes(server, client)?;
+
+    let server_seed = prg::random_seed();
+    let client_seed = prg::random_seed();
+    let r_a = Prg::with_stream(server_seed, RA_STREAM).random_matrix(n, k, MASK_RANGE)?;
+    let s_a = Prg::with_stream(server_seed, SA_STREAM).random_matrix(n, m, MASK_RANGE)?;
+    let r_b = Prg::from_seed(client_seed).random_matrix(k, m, MASK_RANGE)?;
+    let s_b = sub(&mul(&r_a, &r_b)?, &s_a)?;
+
+    prg::send_seed(server, &server_seed)?;
+    prg::send_seed(client, &client_seed)?;
+    send(client, &s_b)?;
+
+    Ok(())
+}
+
+// A + Ra を送って B + Rb を受け取り、ra - Ra (B + Rb) を計算する
+// raはB + Rbの列数がわかってから用意する
+fn server_masked_product<C, F>(comm: &mut C, a: &Matrix, r_a: &Matrix, s_a: F) -> Result<Matrix>
+where
+    C: Communicator,
+    F: FnOnce(usize) -> Result<Matrix>,
+{
+    send(comm, &add(a, r_a)?)?;
+    let b_hat = receive(comm)?;
+    if b_hat.rows() != a.cols() {
+        return Err(anyhow::anyhow!(
+            "Expected B + Rb to have {} rows but received {:?}",
+            a.cols(),
+            b_hat.shape()
+        ));
+    }
+    let s_a = s_a(b_hat.cols())?;
+    check_shape("ra", &s_a, (a.rows(), b_hat.cols()))?;
+
+    sub(&s_a, &mul(r_a, &b_hat)?)
+}
+
+// B + Rb を送って A + Ra を受け取り、(A + Ra) B + rb を計算する
+fn client_masked_product<C>(comm: &mut C, b: &Matrix, r_b: &Matrix, s_b: &Matrix) -> Result<Matrix>
+where
+    C: Communicator,
+{
+    let (k, m) = b.shape();
+    if s_b.cols() != m {
+        return Err(anyhow::anyhow!(
+            "Expected rb to have {} columns but received {:?}",
+            m,
+            s_b.shape()
+        ));
+    }
+
+    send(comm, &add(b, r_b)?)?;
+    let a_hat = receive(comm)?;
+    check_shape("A + Ra", &a_hat, (s_b.rows(), k))?;
+
+    add(&mul(&a_hat, b)?, s_b)
+}
+
+// 予備校側の処理(ディーラーあり)。返り値は積ABの予備校側のシェア
+pub fn server_multiply_with_dealer<C, D>(comm: &mut C, dealer: &mut D, a: &Matrix) -> Result<Matrix>
+where
+    C: Communicator,
+    D: Communicator,
+{
+    send_shape(dealer, a.shape())?;
+    let r_a = receive(dealer)?;
+    check_shape("Ra", &r_a, a.shape())?;
+    let s_a = receive(dealer)?;
+
+    server_masked_product(comm, a, &r_a, |_| Ok(s_a))
+}
+
+// 中学側の処理(ディーラーあり)。返り値は積ABの中学側のシェア
+pub fn client_multiply_with_dealer<C, D>(comm: &mut C, dealer: &mut D, b: &Matrix) -> Result<Matrix>
+where
+    C: Communicator,
+    D: Communicator,
+{
+    send_shape(dealer, b.shape())?;
+    let r_b = receive(dealer)?;
+    check_shape("Rb", &r_b, b.shape())?;
+    let s_b = receive(dealer)?;
+
+    client_masked_product(comm, b, &r_b, &s_b)
+}
+
+// 予備校側の処理(シードを使うディーラーあり)
+pub fn server_multiply_with_seeded_dealer<C, D>(
+    comm: &mut C,
+    dealer: &mut D,
+    a: &Matrix,
+) -> Result<Matrix>
+where
+    C: Communicator,
+    D: Communicator,
+{
+    let (n, k) = a.shape();
+    send_shape(dealer, (n, k))?;
+    let seed = prg::receive_seed(dealer)?;
+    let r_a = Prg::with_stream(seed, RA_STREAM).random_matrix(n, k, MASK_RANGE)?;
+
+    server_masked_product(comm, a, &r_a, |m| {
+        Prg::with_stream(seed, SA_STREAM).random_matrix(n, m, MASK_RANGE)
+    })
+}
+
+// 中学側の処理(シードを使うディーラーあり)
+pub fn client_multiply_with_seeded_dealer<C, D>(
+    comm: &mut C,
+    dealer: &mut D,
+    b: &Matrix,
+) -> Result<Matrix>
+where
+    C: Communicator,
+    D: Communicator,
+{
+    let (k, m) = b.shape();
+    send_shape(dealer, (k, m))?;
+    let seed = prg::receive_seed(dealer)?;
+    let r_b = Prg::from_seed(seed).random_matrix(k, m, MASK_RANGE)?;
+    let s_b = receive(dealer)?;
+
+    client_masked_product(comm, b, &r_b, &s_b)
+}
+
+// シェアを交換して積を復元する。両者が呼ぶ必要がある
+pub fn reveal<C: Communicator>(comm: &mut C, share: &Matrix) -> Result<Matrix> {
+    send(comm, share)?;
+    let other = receive(comm)?;
+    check_shape("the peer's share", &other, share.shape())?;
+
+    add(share, &other)
+}
+
+fn random_matrix<R: Rng + ?Sized>(rows: usize, cols: usize, rng: &mut R) -> Result<Matrix> {
+    Ok(Matrix::from_fn(rows, cols, |_, _| {
+        rng.gen_range(-MASK_RANGE..MASK_RANGE)
+    })?)
+}
+
+fn random_invertible<R: Rng + ?Sized>(size: usize, rng: &mut R) -> Result<Matrix> {
+    for _ in 0..MAX_INVERT_ATTEMPTS {
+        let m = Matrix::from_fn(size, size, |_, _| rng.gen_range(-1.0..1.0))?;
+        let well_conditioned = invert(&m)
+            .map(|inv| mul(&m, &inv))
+            .transpose()?
+            .is_some_and(|id| {
+                id.iter_rows().enumerate().all(|(i, row)| {
+                    row.iter().enumerate().all(|(j, &x)| {
+                        let expected = if i == j { 1.0 } else { 0.0 };
+                        (x - expected).abs() < INVERT_TOLERANCE
+                    })
+                })
+            });
+        if well_conditioned {
+            return Ok(m);
+        }
+    }
+
+    Err(anyhow::anyhow!(
+        "Could not generate an invertible {}x{} matrix",
+        size,
+        size
+    ))
+}
+
+fn zip(a: &Matrix, b: &Matrix, f: impl Fn(f64, f64) -> f64) -> Result<Matrix> {
+    if a.shape() != b.shape() {
+        return Err(anyhow::anyhow!(
+            "Matrix shapes do not match: {:?} and {:?}",
+            a.shape(),
+            b.shape()
+        ));
+    }
+    let data = a
+        .as_slice()
+        .iter()
+        .zip(b.as_slice())
+        .map(|(&x, &y)| f(x, y))
+        .collect();
+
+    Ok(Matrix::new(a.rows(), a.cols(), data)?)
+}
+
+fn add(a: &Matrix, b: &Matrix) -> Result<Matrix> {
+    zip(a, b, |x, y| x + y)
+}
+
+fn sub(a: &Matrix, b: &Matrix) -> Result<Matrix> {
+    zip(a, b, |x, y| x - y)
+}
+
+fn mul(a: &Matrix, b: &Matrix) -> Result<Matrix> {
+    if a.cols() != b.rows() {
+        return Err(anyhow::anyhow!(
+            "Cannot multiply a {:?} matrix by a {:?} matrix",
+            a.shape(),
+            b.shape()
+        ));
+    }
+
+    Ok(Matrix::from_fn(a.rows(), b.cols(), |i, j| {
+        (0..a.cols()).map(|k| a[(i, k)] * b[(k, j)]).sum()
+    })?)
+}
+
+fn split_cols(m: &Matrix, at: usize) -> Result<(Matrix, Matrix)> {
+    let left = Matrix::from_fn(m.rows(), at, |i, j| m[(i, j)])?;
+    let right = Matrix::from_fn(m.rows(), m.cols() - at, |i, j| m[(i, at + j)])?;
+
+    Ok((left, right))
+}
+
+fn split_rows(m: &Matrix, at: usize) -> Result<(Matrix, Matrix)> {
+    let top = Matrix::from_fn(at, m.cols(), |i, j| m[(i, j)])?;
+    let bottom = Matrix::from_fn(m.rows() - at, m.cols(), |i, j| m[(at + i, j)])?;
+
+    Ok((top, bottom))
+}
+
+// 部分ピボット選択付きのGauss-Jordan法。特異な場合はNone
+fn invert(m: &Matrix) -> Option<Matrix> {
+    let n = m.rows();
+    if m.cols() != n {
+        return None;
+    }
+
+    let mut a = m.clone();
+    let mut inv = Matrix::from_fn(n, n, |i, j| if i == j { 1.0 } else { 0.0 }).ok()?;
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&x, &y| a[(x, col)].abs().total_cmp(&a[(y, col)].abs()))?;
+        if a[(pivot, col)].abs() < f64::EPSILON {
+            return None;
+        }
+        for j in 0..n {
+            let (p, c) = (a[(pivot, j)], a[(col, j)]);
+            a[(pivot, j)] = c;
+            a[(col, j)] = p;
+            let (p, c) = (inv[(pivot, j)], inv[(col, j)]);
+            inv[(pivot, j)] = c;
+            inv[(col, j)] = p;
+        }
+
+        let d = a[(col, col)];
+        for j in 0..n {
+            a[(col, j)] /= d;
+            inv[(col, j)] /= d;
+        }
+        for i in (0..n).filter(|&i| i != col) {
+            let factor = a[(i, col)];
+            for j in 0..n {
+                a[(i, j)] -= factor * a[(col, j)];
+                inv[(i, j)] -= factor * inv[(col, j)];
+            }
+        }
+    }
+
+    Some(inv)
+}
@@ -0,0 +1,352 @@
+use crate::codec::Codec;
+use crate::comm::{Communicator, CommunicatorCore, Framing};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret};
+
+// 暗号化通信。鍵は事前に共有した合言葉か、X25519の鍵交換で用意する
+// 各メッセージをChaCha20-Poly1305で暗号化し、通し番号で再送や順序の入れ替えを検出する
+
+const KEY_LEN: usize = 32;
+const HELLO_LEN: usize = 32;
+const SEQ_LEN: usize = 8;
+
+const PBKDF2_SALT: &[u8] = b"se_rust encrypted communicator";
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+// 鍵の確認のために最初に暗号化して送るメッセージ
+const CONFIRMATION: &[u8] = b"se-encrypted";
+
+// 合言葉から導出した鍵
+// 導出は意図的に重くしてあるので、同じ合言葉で何度も接続する場合は使い回すこと
+#[derive(Clone)]
+pub struct PresharedKey([u8; KEY_LEN]);
+
+impl PresharedKey {
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let mut key = [0; KEY_LEN];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), PBKDF2_SALT, PBKDF2_ROUNDS, &mut key);
+
+        Self(key)
+    }
+
+    pub fn from_bytes(key: [u8; KEY_LEN]) -> Self {
+        Self(key)
+    }
+}
+
+// 鍵がログに出ないようにする
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PresharedKey(..)")
+    }
+}
+
+pub struct EncryptedCommunicator<C: Communicator> {
+    inner: C,
+    send_cipher: ChaCha20Poly1305,
+    receive_cipher: ChaCha20Poly1305,
+    send_seq: u64,
+    receive_seq: u64,
+}
+
+// 鍵交換に使うX25519の鍵ペア。相手に認証させるための長期的な鍵として使う
+#[derive(Clone)]
+pub struct StaticKeypair {
+    secret: StaticSecret,
+    public: X25519PublicKey,
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn from_secret_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self::from_secret(StaticSecret::from(bytes))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = X25519PublicKey::from(&secret);
+
+        Self { secret, public }
+    }
+
+    pub fn secret_bytes(&self) -> [u8; KEY_LEN] {
+        self.secret.to_bytes()
+    }
+
+    // 相手に事前に渡しておく公開鍵
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.public.to_bytes()
+    }
+}
+
+// 秘密鍵がログに出ないようにする
+impl fmt::Debug for StaticKeypair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticKeypair")
+            .field("public", &self.public.as_bytes())
+            .finish_non_exhaustive()
+    }
+}
+
+// X25519による鍵交換の設定
+// 何も設定しない場合は一時鍵だけで鍵を共有するので、盗聴は防げるが中間者攻撃は防げない
+// 相手の公開鍵を`peer_key`で指定すると、その鍵の持ち主とだけ通信できる
+#[derive(Debug, Clone, Default)]
+pub struct Handshake {
+    static_key: Option<StaticKeypair>,
+    peer_key: Option<[u8; KEY_LEN]>,
+}
+
+impl Handshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 自分の長期鍵。相手が公開鍵を指定している場合に必要
+    pub fn static_key(mut self, keypair: StaticKeypair) -> Self {
+        self.static_key = Some(keypair);
+        self
+    }
+
+    // 相手の長期鍵の公開鍵
+    pub fn peer_key(mut self, public_key: [u8; KEY_LEN]) -> Self {
+        self.peer_key = Some(public_key);
+        self
+    }
+}
+
+fn diffie_hellman(secret: &StaticSecret, public: &X25519PublicKey) -> anyhow::Result<Vec<u8>> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(anyhow::anyhow!("Peer sent a low order public key"));
+    }
+
+    Ok(shared.as_bytes().to_vec())
+}
+
+fn parse_public_key(bytes: &[u8]) -> X25519PublicKey {
+    let bytes: [u8; KEY_LEN] = bytes.try_into().unwrap();
+
+    X25519PublicKey::from(bytes)
+}
+
+impl<C> EncryptedCommunicator<C>
+where
+    C: Communicator + CommunicatorCore,
+{
+    // 相手も同じ鍵でこの関数を呼ぶ必要がある
+    // 合言葉が一致しない場合は双方でエラーになる
+    pub fn new(mut inner: C, key: &PresharedKey) -> anyhow::Result<Self> {
+        // 暗号文はバイナリなので長さ付きのフレームで送る
+        inner.set_framing(Framing::LengthPrefixed);
+
+        // 双方の乱数から接続ごとの鍵を導出する
+        let mut hello = [0; HELLO_LEN];
+        OsRng.fill_bytes(&mut hello);
+        inner.send(&hello)?;
+        let peer_hello = inner.receive()?;
+        if peer_hello.len() != HELLO_LEN {
+            return Err(anyhow::anyhow!("Peer did not start an encrypted session"));
+        }
+
+        Self::establish(inner, &key.0, &hello, &peer_hello)
+    }
+
+    // X25519の鍵交換で鍵を共有する。相手も同じ関数を呼ぶ必要がある
+    // 一時鍵の公開鍵(と、あれば長期鍵の公開鍵)を送り合い、
+    // Noiseと同様に一時鍵同士・長期鍵と一時鍵の鍵共有の結果をすべて混ぜて鍵を導出する
+    pub fn with_handshake(mut inner: C, handshake: &Handshake) -> anyhow::Result<Self> {
+        inner.set_framing(Framing::LengthPrefixed);
+
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let mut hello = X25519PublicKey::from(&ephemeral).to_bytes().to_vec();
+        if let Some(keypair) = &handshake.static_key {
+            hello.extend(keypair.public_key());
+        }
+        inner.send(&hello)?;
+
+        let peer_hello = inner.receive()?;
+        let (peer_ephemeral, peer_static) = match peer_hello.len() {
+            KEY_LEN => (parse_public_key(&peer_hello), None),
+            len if len == 2 * KEY_LEN => (
+                parse_public_key(&peer_hello[..KEY_LEN]),
+                Some(parse_public_key(&peer_hello[KEY_LEN..])),
+            ),
+            _ => return Err(anyhow::anyhow!("Peer did not start a handshake")),
+        };
+        if let Some(expected) = handshake.peer_key {
+            match peer_static {
+                Some(key) if key.to_bytes() == expected => {}
+                Some(_) => return Err(anyhow::anyhow!("Peer presented an unexpected static key")),
+                None => return Err(anyhow::anyhow!("Peer did not present a static key")),
+            }
+        }
+
+        // 自分の長期鍵と相手の一時鍵、自分の一時鍵と相手の長期鍵
+        let mine = handshake
+            .static_key
+            .as_ref()
+            .map(|keypair| diffie_hellman(&keypair.secret, &peer_ephemeral))
+            .transpose()?;
+        let theirs = peer_static
+            .map(|key| diffie_hellman(&ephemeral, &key))
+            .transpose()?;
+
+        // 双方で同じ順序になるよう、挨拶が小さい側の長期鍵を使った結果を先にする
+        let (first, second) = if hello < peer_hello {
+            (mine, theirs)
+        } else {
+            (theirs, mine)
+        };
+        let mut secret = diffie_hellman(&ephemeral, &peer_ephemeral)?;
+        secret.extend(first.into_iter().flatten());
+        secret.extend(second.into_iter().flatten());
+
+        Self::establish(inner, &secret, &hello, &peer_hello)
+    }
+
+    // 共有した秘密と双方の挨拶から方向ごとの鍵を導出し、互いに確認する
+    fn establish(inner: C, secret: &[u8], hello: &[u8], peer_hello: &[u8]) -> anyhow::Result<Self> {
+        if peer_hello == hello {
+            return Err(anyhow::anyhow!("Peer echoed our hello"));
+        }
+
+        // 挨拶が小さい方から大きい方への鍵と、その逆向きの鍵
+        let is_low = hello < peer_hello;
+        let (low, high) = if is_low {
+            (hello, peer_hello)
+        } else {
+            (peer_hello, hello)
+        };
+        let hkdf = Hkdf::<Sha256>::new(Some(&[low, high].concat()), secret);
+        let low_to_high = expand(&hkdf, b"se_rust low to high")?;
+        let high_to_low = expand(&hkdf, b"se_rust high to low")?;
+        let (send_cipher, receive_cipher) = if is_low {
+            (low_to_high, high_to_low)
+        } else {
+            (high_to_low, low_to_high)
+        };
+
+        let mut comm = Self {
+            inner,
+            send_cipher,
+            receive_cipher,
+            send_seq: 0,
+            receive_seq: 0,
+        };
+        comm.confirm()?;
+
+        Ok(comm)
+    }
+}
+
+impl<C: Communicator> EncryptedCommunicator<C> {
+    // 双方が同じ鍵を持っていることを確認する
+    fn confirm(&mut self) -> anyhow::Result<()> {
+        self.send(CONFIRMATION)?;
+        let confirmation = self.receive().map_err(|e| {
+            anyhow::anyhow!("Key confirmation failed, the keys probably differ: {}", e)
+        })?;
+        if confirmation != CONFIRMATION {
+            return Err(anyhow::anyhow!("Key confirmation failed"));
+        }
+
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+fn expand(hkdf: &Hkdf<Sha256>, info: &[u8]) -> anyhow::Result<ChaCha20Poly1305> {
+    let mut key = [0; KEY_LEN];
+    hkdf.expand(info, &mut key)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+// 鍵は接続ごと・方向ごとに異なるので、通し番号をそのままnonceに使える
+fn nonce(seq: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&seq.to_be_bytes());
+
+    nonce
+}
+
+impl<C: Communicator> Communicator for EncryptedCommunicator<C> {
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        let seq = self.send_seq.to_be_bytes();
+        let ciphertext = self
+            .send_cipher
+            .encrypt(
+                &nonce(self.send_seq),
+                Payload {
+                    msg: data,
+                    aad: &seq,
+                },
+            )
+            .map_err(|_| Error::other("Encryption failed"))?;
+        self.send_seq = self
+            .send_seq
+            .checked_add(1)
+            .ok_or_else(|| Error::other("Sequence number overflowed"))?;
+
+        self.inner.send(&[&seq[..], &ciphertext].concat())
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>> {
+        let frame = self.inner.receive()?;
+        if frame.len() < SEQ_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "Frame is too short"));
+        }
+
+        let (seq, ciphertext) = frame.split_at(SEQ_LEN);
+        let seq_number = u64::from_be_bytes(seq.try_into().unwrap());
+        if seq_number != self.receive_seq {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Expected frame {} but received frame {}; it was replayed or reordered",
+                    self.receive_seq, seq_number
+                ),
+            ));
+        }
+
+        let data = self
+            .receive_cipher
+            .decrypt(
+                &nonce(seq_number),
+                Payload {
+                    msg: ciphertext,
+                    aad: seq,
+                },
+            )
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Message authentication failed"))?;
+        self.receive_seq += 1;
+
+        Ok(data)
+    }
+
+    fn codec(&self) -> Codec {
+        self.inner.codec()
+    }
+
+    fn set_codec(&mut self, codec: Codec) -> Result<()> {
+        self.inner.set_codec(codec)
+    }
+}
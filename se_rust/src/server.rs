@@ -1,11 +1,30 @@
-use crate::comm::{ChannelCommunicator, Communicator, TcpCommunicator};
+use crate::codec::Codec;
+use crate::comm::{
+    ChannelCommunicator, Communicator, CommunicatorCore, Framing, IpVersion, TcpCommunicator,
+};
+use crate::secure::{EncryptedCommunicator, Handshake};
+#[cfg(feature = "tls")]
+use crate::tls::{TlsCommunicator, TlsIdentity, TlsServer};
 use anyhow::Result;
 use crossbeam_channel::{Receiver, Sender};
+use socket2::{Domain, Protocol, Socket, Type};
 use std::io::BufReader;
-use std::net::TcpListener;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 pub struct Server<C: Communicator>(C);
 
+impl<C: Communicator> Server<C> {
+    pub fn get_ref(&self) -> &C {
+        &self.0
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.0
+    }
+}
+
 impl<C> Communicator for Server<C>
 where
     C: Communicator,
@@ -17,24 +36,271 @@ where
     fn receive(&mut self) -> std::io::Result<Vec<u8>> {
         self.0.receive()
     }
+
+    fn codec(&self) -> Codec {
+        self.0.codec()
+    }
+
+    fn set_codec(&mut self, codec: Codec) -> std::io::Result<()> {
+        self.0.set_codec(codec)
+    }
+}
+
+impl<C> CommunicatorCore for Server<C>
+where
+    C: Communicator + CommunicatorCore,
+{
+    type Sender = C::Sender;
+    type Receiver = C::Receiver;
+
+    fn get_sender(&mut self) -> &mut Self::Sender {
+        self.0.get_sender()
+    }
+
+    fn get_receiver(&mut self) -> &mut BufReader<Self::Receiver> {
+        self.0.get_receiver()
+    }
+
+    fn framing(&self) -> Framing {
+        self.0.framing()
+    }
+
+    fn set_framing(&mut self, framing: Framing) {
+        self.0.set_framing(framing)
+    }
+
+    fn partial_frame(&mut self) -> Option<&mut Vec<u8>> {
+        self.0.partial_frame()
+    }
+
+    fn read_timeout(&self) -> std::io::Result<Option<Duration>> {
+        self.0.read_timeout()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        CommunicatorCore::set_read_timeout(&mut self.0, timeout)
+    }
 }
 
-const ADDRESS: &'static str = "127.0.0.1";
-const PORT: &'static str = "10000";
+pub const PORT: u16 = 10000;
 
 pub type TcpServer = Server<TcpCommunicator>;
 
 impl TcpServer {
     pub fn new() -> Result<TcpServer> {
-        let listener = TcpListener::bind(format!("{}:{}", ADDRESS, PORT))?;
-        let (stream, addr) = listener.accept()?;
-        println!("Connection from {:?}", addr);
-        let receiver = BufReader::new(stream.try_clone()?);
+        Self::builder().build()
+    }
 
-        Ok(Self(TcpCommunicator {
-            sender: stream,
-            receiver,
-        }))
+    pub fn with_framing(framing: Framing) -> Result<TcpServer> {
+        Self::builder().framing(framing).build()
+    }
+
+    pub fn builder() -> TcpServerBuilder {
+        TcpServerBuilder::new()
+    }
+}
+
+// アドレスを指定しなければIPバージョンに応じたループバックアドレスで待ち受ける
+#[derive(Debug, Clone)]
+pub struct TcpServerBuilder {
+    address: Option<String>,
+    port: u16,
+    ip_version: IpVersion,
+    reuse_address: bool,
+    framing: Framing,
+    codec: Codec,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl Default for TcpServerBuilder {
+    fn default() -> Self {
+        Self {
+            address: None,
+            port: PORT,
+            ip_version: IpVersion::default(),
+            // std::net::TcpListener::bindと同じくUnixでは既定で有効にする
+            reuse_address: cfg!(unix),
+            framing: Framing::default(),
+            codec: Codec::default(),
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+}
+
+impl TcpServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn address(mut self, address: &str) -> Self {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.ip_version = ip_version;
+        self
+    }
+
+    pub fn reuse_address(mut self, reuse_address: bool) -> Self {
+        self.reuse_address = reuse_address;
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    fn bind(&self) -> Result<TcpListener> {
+        let host = self
+            .address
+            .as_deref()
+            .unwrap_or_else(|| self.ip_version.localhost());
+        let addr = self.ip_version.resolve(host, self.port)?[0];
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(self.reuse_address)?;
+        socket.bind(&addr.into())?;
+        socket.listen(128)?;
+
+        Ok(socket.into())
+    }
+
+    // 待ち受けだけ行い、接続の受け付けは`TcpServerListener`に任せる
+    pub fn listen(self) -> Result<TcpServerListener> {
+        let listener = self.bind()?;
+
+        Ok(TcpServerListener {
+            listener,
+            framing: self.framing,
+            codec: self.codec,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    pub fn build(self) -> Result<TcpServer> {
+        let session = self.listen()?.accept()?;
+
+        Ok(session.server)
+    }
+
+    // 接続後にX25519の鍵交換を行い、暗号化された通信路を返す
+    pub fn build_encrypted(
+        self,
+        handshake: &Handshake,
+    ) -> Result<EncryptedCommunicator<TcpServer>> {
+        EncryptedCommunicator::with_handshake(self.build()?, handshake)
+    }
+
+    // 接続後にTLSのハンドシェイクを行う。証明書はクライアントに事前に伝えておく
+    #[cfg(feature = "tls")]
+    pub fn build_tls(self, identity: &TlsIdentity) -> Result<TlsServer> {
+        let session = self.listen()?.accept_tls(identity)?;
+
+        Ok(session.server)
+    }
+}
+
+// 複数の中学側クライアントを受け付けるためのリスナー
+pub struct TcpServerListener {
+    listener: TcpListener,
+    framing: Framing,
+    codec: Codec,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    next_id: AtomicU64,
+}
+
+pub struct Session<S = TcpServer> {
+    pub id: u64,
+    pub peer_addr: SocketAddr,
+    pub server: S,
+}
+
+impl TcpServerListener {
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    fn accept_stream(&self) -> Result<(u64, SocketAddr, TcpStream)> {
+        let (stream, peer_addr) = self.listener.accept()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        println!("Connection from {:?} (session {})", peer_addr, id);
+
+        Ok((id, peer_addr, stream))
+    }
+
+    pub fn accept(&self) -> Result<Session> {
+        let (id, peer_addr, stream) = self.accept_stream()?;
+
+        let mut comm = TcpCommunicator::new(stream, self.framing)?;
+        comm.set_codec(self.codec)?;
+        comm.set_read_timeout(self.read_timeout)?;
+        comm.set_write_timeout(self.write_timeout)?;
+
+        Ok(Session {
+            id,
+            peer_addr,
+            server: Server(comm),
+        })
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn accept_tls(&self, identity: &TlsIdentity) -> Result<Session<TlsServer>> {
+        let (id, peer_addr, stream) = self.accept_stream()?;
+        // ハンドシェイク中もタイムアウトが効くように先に設定する
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+
+        let mut comm = TlsCommunicator::accept(stream, identity, self.framing)?;
+        comm.set_codec(self.codec)?;
+
+        Ok(Session {
+            id,
+            peer_addr,
+            server: Server(comm),
+        })
+    }
+
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+}
+
+pub struct Incoming<'a> {
+    listener: &'a TcpServerListener,
+}
+
+impl<'a> Iterator for Incoming<'a> {
+    type Item = Result<Session>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept())
     }
 }
 
@@ -46,4 +312,14 @@ impl ChannelServer {
 
         Self(cc)
     }
+
+    pub fn with_framing(
+        rx: Receiver<Vec<u8>>,
+        tx: Sender<Vec<u8>>,
+        framing: Framing,
+    ) -> ChannelServer {
+        let cc = ChannelCommunicator::with_framing(rx, tx, framing);
+
+        Self(cc)
+    }
 }
@@ -0,0 +1,414 @@
+use crate::comm::Communicator;
+use crate::matrix::Matrix;
+use anyhow::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// 2^61 - 1 (メルセンヌ素数)
+pub const DEFAULT_MODULUS: u64 = (1 << 61) - 1;
+
+// 秘密分散に参加する二者。予備校側がServer、中学側がClient
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Party {
+    Server,
+    Client,
+}
+
+// 素数pを法とする有限体
+// 加算でu64があふれないよう、pは2^63未満に制限している
+// 相手から受け取った場合も`Field::new`で検証するため、法だけを送る
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u64", into = "u64")]
+pub struct Field {
+    modulus: u64,
+}
+
+impl Default for Field {
+    fn default() -> Self {
+        Self {
+            modulus: DEFAULT_MODULUS,
+        }
+    }
+}
+
+impl TryFrom<u64> for Field {
+    type Error = anyhow::Error;
+
+    fn try_from(modulus: u64) -> Result<Self> {
+        Self::new(modulus)
+    }
+}
+
+impl From<Field> for u64 {
+    fn from(field: Field) -> Self {
+        field.modulus
+    }
+}
+
+impl Field {
+    pub fn new(modulus: u64) -> Result<Self> {
+        if modulus >= 1 << 63 {
+            return Err(anyhow::anyhow!(
+                "Modulus {} must be smaller than 2^63",
+                modulus
+            ));
+        }
+        if !is_prime(modulus) {
+            return Err(anyhow::anyhow!("Modulus {} is not a prime", modulus));
+        }
+
+        Ok(Self { modulus })
+    }
+
+    pub fn modulus(&self) -> u64 {
+        self.modulus
+    }
+
+    pub fn contains(&self, x: u64) -> bool {
+        x < self.modulus
+    }
+
+    pub fn add(&self, a: u64, b: u64) -> u64 {
+        (a + b) % self.modulus
+    }
+
+    pub fn sub(&self, a: u64, b: u64) -> u64 {
+        (a + self.modulus - b) % self.modulus
+    }
+
+    pub fn neg(&self, a: u64) -> u64 {
+        self.sub(0, a)
+    }
+
+    pub fn mul(&self, a: u64, b: u64) -> u64 {
+        ((a as u128 * b as u128) % self.modulus as u128) as u64
+    }
+
+    pub fn random<R: Rng + ?Sized>(&self, rng: &mut R) -> u64 {
+        rng.gen_range(0..self.modulus)
+    }
+
+    // 負の数はp - |x|として埋め込む
+    pub fn encode(&self, x: i64) -> u64 {
+        x.rem_euclid(self.modulus as i64) as u64
+    }
+
+    // p/2より大きい値は負の数とみなして戻す
+    pub fn decode(&self, x: u64) -> i64 {
+        if x > self.modulus / 2 {
+            -((self.modulus - x) as i64)
+        } else {
+            x as i64
+        }
+    }
+
+    pub fn encode_matrix(&self, matrix: &Matrix<i64>) -> Matrix<u64> {
+        map_matrix(matrix, |&x| self.encode(x))
+    }
+
+    pub fn decode_matrix(&self, matrix: &Matrix<u64>) -> Matrix<i64> {
+        map_matrix(matrix, |&x| self.decode(x))
+    }
+
+    pub fn random_matrix<R: Rng + ?Sized>(
+        &self,
+        rows: usize,
+        cols: usize,
+        rng: &mut R,
+    ) -> Result<Matrix<u64>> {
+        Ok(Matrix::from_fn(rows, cols, |_, _| self.random(rng))?)
+    }
+
+    pub(crate) fn zip_matrix<F>(
+        &self,
+        a: &Matrix<u64>,
+        b: &Matrix<u64>,
+        f: F,
+    ) -> Result<Matrix<u64>>
+    where
+        F: Fn(&Self, u64, u64) -> u64,
+    {
+        if a.shape() != b.shape() {
+            return Err(anyhow::anyhow!(
+                "Matrix shapes do not match: {:?} and {:?}",
+                a.shape(),
+                b.shape()
+            ));
+        }
+
+        let data = a
+            .as_slice()
+            .iter()
+            .zip(b.as_slice())
+            .map(|(&x, &y)| f(self, x, y))
+            .collect();
+
+        Ok(Matrix::new(a.rows(), a.cols(), data)?)
+    }
+
+    pub fn add_matrix(&self, a: &Matrix<u64>, b: &Matrix<u64>) -> Result<Matrix<u64>> {
+        self.zip_matrix(a, b, Field::add)
+    }
+
+    pub fn sub_matrix(&self, a: &Matrix<u64>, b: &Matrix<u64>) -> Result<Matrix<u64>> {
+        self.zip_matrix(a, b, Field::sub)
+    }
+
+    // 要素ごとの積
+    pub fn mul_elementwise(&self, a: &Matrix<u64>, b: &Matrix<u64>) -> Result<Matrix<u64>> {
+        self.zip_matrix(a, b, Field::mul)
+    }
+
+    pub fn mul_matrix(&self, a: &Matrix<u64>, b: &Matrix<u64>) -> Result<Matrix<u64>> {
+        if a.cols() != b.rows() {
+            return Err(anyhow::anyhow!(
+                "Cannot multiply a {:?} matrix by a {:?} matrix",
+                a.shape(),
+                b.shape()
+            ));
+        }
+
+        Ok(Matrix::from_fn(a.rows(), b.cols(), |i, j| {
+            (0..a.cols()).fold(0, |acc, k| self.add(acc, self.mul(a[(i, k)], b[(k, j)])))
+        })?)
+    }
+
+    fn check_matrix(&self, matrix: &Matrix<u64>) -> Result<()> {
+        match matrix.as_slice().iter().find(|&&x| !self.contains(x)) {
+            Some(x) => Err(anyhow::anyhow!(
+                "Value {} is not an element of the field modulo {}",
+                x,
+                self.modulus
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+fn map_matrix<T, U, F: FnMut(&T) -> U>(matrix: &Matrix<T>, f: F) -> Matrix<U> {
+    let data = matrix.as_slice().iter().map(f).collect();
+
+    Matrix::new(matrix.rows(), matrix.cols(), data).unwrap()
+}
+
+fn mod_pow(base: u64, mut exp: u64, modulus: u64) -> u64 {
+    let m = modulus as u128;
+    let mut base = base as u128 % m;
+    let mut result = 1u128;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result * base % m;
+        }
+        base = base * base % m;
+        exp >>= 1;
+    }
+
+    result as u64
+}
+
+// u64の範囲で決定的なMiller-Rabin判定
+fn is_prime(n: u64) -> bool {
+    const BASES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+    if n < 2 {
+        return false;
+    }
+    if let Some(&p) = BASES.iter().find(|&&p| n.is_multiple_of(p)) {
+        return n == p;
+    }
+
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+
+    BASES.iter().all(|&a| {
+        let mut x = mod_pow(a, d, n);
+        if x == 1 || x == n - 1 {
+            return true;
+        }
+        for _ in 1..s {
+            x = mod_pow(x, 2, n);
+            if x == n - 1 {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+// 加法的秘密分散のシェア。二者のシェアを足すと元の値になる
+// 受信したシェアも`Share::new`で検証するため、体の元でない値を含むものは復元できない
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawShare")]
+pub struct Share {
+    field: Field,
+    values: Matrix<u64>,
+}
+
+#[derive(Deserialize)]
+struct RawShare {
+    field: Field,
+    values: Matrix<u64>,
+}
+
+impl TryFrom<RawShare> for Share {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawShare) -> Result<Self> {
+        Self::new(raw.field, raw.values)
+    }
+}
+
+impl Share {
+    pub fn new(field: Field, values: Matrix<u64>) -> Result<Self> {
+        field.check_matrix(&values)?;
+
+        Ok(Self { field, values })
+    }
+
+    pub fn field(&self) -> Field {
+        self.field
+    }
+
+    pub fn values(&self) -> &Matrix<u64> {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Matrix<u64> {
+        self.values
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        self.values.shape()
+    }
+
+    // 受信したシェアが指定した体の元からなることを確認する
+    pub(crate) fn validate(&self, field: Field) -> Result<()> {
+        if self.field != field {
+            return Err(anyhow::anyhow!(
+                "Received a share modulo {} but expected modulo {}",
+                self.field.modulus,
+                field.modulus
+            ));
+        }
+
+        field.check_matrix(&self.values)
+    }
+
+    fn check_field(&self, other: &Share) -> Result<()> {
+        if self.field != other.field {
+            return Err(anyhow::anyhow!(
+                "Shares belong to different fields: {} and {}",
+                self.field.modulus,
+                other.field.modulus
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&self, other: &Share) -> Result<Share> {
+        self.check_field(other)?;
+        let values = self.field.add_matrix(&self.values, &other.values)?;
+
+        Ok(Share {
+            field: self.field,
+            values,
+        })
+    }
+
+    pub fn sub(&self, other: &Share) -> Result<Share> {
+        self.check_field(other)?;
+        let values = self.field.sub_matrix(&self.values, &other.values)?;
+
+        Ok(Share {
+            field: self.field,
+            values,
+        })
+    }
+
+    // 公開値の加算は片方(Server)だけが行う
+    pub fn add_public(&self, public: &Matrix<u64>, party: Party) -> Result<Share> {
+        self.field.check_matrix(public)?;
+        let values = match party {
+            Party::Server => self.field.add_matrix(&self.values, public)?,
+            Party::Client => self.field.zip_matrix(&self.values, public, |_, x, _| x)?,
+        };
+
+        Ok(Share {
+            field: self.field,
+            values,
+        })
+    }
+
+    pub fn scalar_mul(&self, scalar: u64) -> Share {
+        let scalar = scalar % self.field.modulus;
+
+        Share {
+            field: self.field,
+            values: map_matrix(&self.values, |&x| self.field.mul(x, scalar)),
+        }
+    }
+}
+
+pub fn split<R: Rng + ?Sized>(
+    secret: &Matrix<u64>,
+    field: Field,
+    rng: &mut R,
+) -> Result<(Share, Share)> {
+    field.check_matrix(secret)?;
+
+    let (rows, cols) = secret.shape();
+    let mask = field.random_matrix(rows, cols, rng)?;
+    let rest = field.sub_matrix(secret, &mask)?;
+
+    Ok((
+        Share {
+            field,
+            values: mask,
+        },
+        Share {
+            field,
+            values: rest,
+        },
+    ))
+}
+
+pub fn reconstruct(a: &Share, b: &Share) -> Result<Matrix<u64>> {
+    Ok(a.add(b)?.into_values())
+}
+
+pub fn send_share<C: Communicator>(comm: &mut C, share: &Share) -> Result<()> {
+    comm.send_value(share)
+}
+
+pub fn receive_share<C: Communicator>(comm: &mut C, field: Field) -> Result<Share> {
+    let share: Share = comm.receive_value()?;
+    share.validate(field)?;
+
+    Ok(share)
+}
+
+// 秘密を分散して片方のシェアを相手に送り、もう片方を手元に残す
+pub fn share_with_peer<C, R>(
+    comm: &mut C,
+    secret: &Matrix<u64>,
+    field: Field,
+    rng: &mut R,
+) -> Result<Share>
+where
+    C: Communicator,
+    R: Rng + ?Sized,
+{
+    let (mine, theirs) = split(secret, field, rng)?;
+    send_share(comm, &theirs)?;
+
+    Ok(mine)
+}
+
+// 双方のシェアを交換して値を復元する。両者が呼ぶ必要がある
+pub fn open<C: Communicator>(comm: &mut C, share: &Share) -> Result<Matrix<u64>> {
+    send_share(comm, share)?;
+    let other = receive_share(comm, share.field)?;
+
+    reconstruct(share, &other)
+}
@@ -1,7 +1,22 @@
-use crate::client::{ChannelClient, TcpClient};
-use crate::comm::Communicator;
-use crate::server::{ChannelServer, TcpServer};
-use crossbeam_channel::unbounded;
+use crate::beaver::{self, TripleShape};
+use crate::client::{ChannelClient, RetryPolicy, TcpClient, TcpClientBuilder};
+use crate::codec::{negotiate_codec, Codec};
+use crate::comm::{
+    self, is_timeout, negotiate_framing, negotiate_framing_with_timeout, Communicator, Framing,
+    IpVersion, TcpCommunicator,
+};
+use crate::matrix::{self, Element, Matrix, MatrixError};
+use crate::mitm::{self, Direction, Rules};
+use crate::paillier;
+use crate::prg::{self, Prg};
+use crate::product;
+use crate::secure::{EncryptedCommunicator, Handshake, PresharedKey, StaticKeypair};
+use crate::server::{ChannelServer, TcpServer, TcpServerBuilder};
+use crate::sharing::{self, Field, Party, Share};
+use crossbeam_channel::{bounded, unbounded};
+use num_bigint::BigInt;
+use proptest::prelude::*;
+use std::net::{TcpListener, TcpStream};
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use std::{thread, time};
@@ -114,6 +129,36 @@ times: {}",
     );
 }
 
+fn ping_binary<C1, C2>(c1: Arc<Mutex<C1>>, c2: Arc<Mutex<C2>>)
+where
+    C1: Communicator + Sync + Send + 'static,
+    C2: Communicator + Sync + Send + 'static,
+{
+    let messages: Vec<Vec<u8>> = vec![
+        (0..=255).collect(),
+        b"\\n\r\n\\".to_vec(),
+        vec![],
+        vec![b'\n'; 3],
+    ];
+
+    let ms = messages.clone();
+    thread::spawn(move || {
+        let mut c1 = c1.lock().unwrap();
+        for m in ms {
+            c1.send(&m).unwrap();
+        }
+    });
+
+    let t = thread::spawn(move || {
+        let mut c2 = c2.lock().unwrap();
+        for m in messages {
+            assert_eq!(c2.receive().unwrap(), m);
+        }
+    });
+
+    t.join().unwrap();
+}
+
 fn ping_matrix<C1, C2>(c1: Arc<Mutex<C1>>, c2: Arc<Mutex<C2>>, matrix: Vec<Vec<f64>>)
 where
     C1: Communicator + Sync + Send + 'static,
@@ -230,6 +275,182 @@ fn channel_tests() {
     tests_base(Arc::clone(&channel_server), Arc::clone(&channel_client));
 }
 
+#[test]
+fn channel_length_prefixed_tests() {
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+
+    let (channel_server, channel_client) = (
+        ChannelServer::with_framing(s_rx, c_tx, Framing::LengthPrefixed),
+        ChannelClient::with_framing(c_rx, s_tx, Framing::LengthPrefixed),
+    );
+    let channel_server = Arc::new(Mutex::new(channel_server));
+    let channel_client = Arc::new(Mutex::new(channel_client));
+
+    tests_base(Arc::clone(&channel_server), Arc::clone(&channel_client));
+
+    ping_binary(Arc::clone(&channel_server), Arc::clone(&channel_client));
+    ping_binary(Arc::clone(&channel_client), Arc::clone(&channel_server));
+}
+
+#[test]
+fn channel_escaped_line_tests() {
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+
+    let (channel_server, channel_client) = (
+        ChannelServer::with_framing(s_rx, c_tx, Framing::EscapedLine),
+        ChannelClient::with_framing(c_rx, s_tx, Framing::EscapedLine),
+    );
+    let channel_server = Arc::new(Mutex::new(channel_server));
+    let channel_client = Arc::new(Mutex::new(channel_client));
+
+    tests_base(Arc::clone(&channel_server), Arc::clone(&channel_client));
+
+    ping_binary(Arc::clone(&channel_server), Arc::clone(&channel_client));
+    ping_binary(Arc::clone(&channel_client), Arc::clone(&channel_server));
+}
+
+#[test]
+fn legacy_line_misreads_escaped_backslash() {
+    // 旧形式では`\`をエスケープしないので`\n`というリテラルが改行になってしまう
+    let frame = comm::escape_line(b"\\n");
+    assert_eq!(comm::unescape_line(frame), b"\n");
+
+    let frame = comm::escape(b"\\n");
+    assert_eq!(comm::unescape(&frame).unwrap(), b"\\n");
+}
+
+#[test]
+fn unescape_rejects_unknown_sequence() {
+    assert!(comm::unescape(b"\\x").is_err());
+    assert!(comm::unescape(b"\\").is_err());
+}
+
+fn negotiate_pair(
+    server_supported: Vec<Framing>,
+    client_supported: Vec<Framing>,
+) -> (ChannelServer, ChannelClient, Framing) {
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+
+    let mut channel_server = ChannelServer::new(s_rx, c_tx);
+    let mut channel_client = ChannelClient::new(c_rx, s_tx);
+
+    let t = thread::spawn(move || {
+        let framing = negotiate_framing(&mut channel_client, &client_supported).unwrap();
+        (channel_client, framing)
+    });
+    let framing = negotiate_framing(&mut channel_server, &server_supported).unwrap();
+    let (channel_client, client_framing) = t.join().unwrap();
+    assert_eq!(framing, client_framing);
+
+    (channel_server, channel_client, framing)
+}
+
+#[test]
+fn negotiate_framing_tests() {
+    let all = vec![Framing::Line, Framing::EscapedLine, Framing::LengthPrefixed];
+
+    let (server, client, framing) = negotiate_pair(all.clone(), all.clone());
+    assert_eq!(framing, Framing::LengthPrefixed);
+    let server = Arc::new(Mutex::new(server));
+    let client = Arc::new(Mutex::new(client));
+    ping_binary(Arc::clone(&server), Arc::clone(&client));
+    ping_binary(Arc::clone(&client), Arc::clone(&server));
+
+    let (server, client, framing) =
+        negotiate_pair(all.clone(), vec![Framing::Line, Framing::EscapedLine]);
+    assert_eq!(framing, Framing::EscapedLine);
+    let server = Arc::new(Mutex::new(server));
+    let client = Arc::new(Mutex::new(client));
+    tests_base(Arc::clone(&server), Arc::clone(&client));
+    ping_binary(Arc::clone(&server), Arc::clone(&client));
+
+    let (_, _, framing) = negotiate_pair(vec![Framing::Line], all.clone());
+    assert_eq!(framing, Framing::Line);
+
+    // ネゴシエーションしない旧形式の相手とは、返事を待った後にLineで話す
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+    let mut channel_server = ChannelServer::new(s_rx, c_tx);
+    let mut old_client = ChannelClient::new(c_rx, s_tx);
+    let timeout = time::Duration::from_millis(100);
+    let framing = negotiate_framing_with_timeout(&mut channel_server, &all, timeout).unwrap();
+    assert_eq!(framing, Framing::Line);
+    // 旧形式の相手には提案が通常のメッセージとして届く
+    assert!(old_client.receive().unwrap().starts_with(b"se-framing"));
+    old_client.send(b"from old peer").unwrap();
+    assert_eq!(channel_server.receive().unwrap(), b"from old peer");
+    channel_server.send(b"to old peer").unwrap();
+    assert_eq!(old_client.receive().unwrap(), b"to old peer");
+}
+
+fn round_trip(framing: Framing, messages: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+
+    let mut channel_server = ChannelServer::with_framing(s_rx, c_tx, framing);
+    let mut channel_client = ChannelClient::with_framing(c_rx, s_tx, framing);
+
+    for m in messages {
+        channel_server.send(m).unwrap();
+    }
+
+    messages
+        .iter()
+        .map(|_| channel_client.receive().unwrap())
+        .collect()
+}
+
+proptest! {
+    #[test]
+    fn escape_round_trip(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let frame = comm::escape(&data);
+        prop_assert!(!frame.contains(&b'\n'));
+        prop_assert_eq!(comm::unescape(&frame).unwrap(), data);
+    }
+
+    #[test]
+    fn escaped_line_round_trip(
+        messages in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..64), 1..8)
+    ) {
+        prop_assert_eq!(round_trip(Framing::EscapedLine, &messages), messages);
+    }
+
+    #[test]
+    fn length_prefixed_round_trip(
+        messages in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..64), 1..8)
+    ) {
+        prop_assert_eq!(round_trip(Framing::LengthPrefixed, &messages), messages);
+    }
+}
+
+fn prepare_tcp_communicators(framing: Framing) -> (TcpCommunicator, TcpCommunicator) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = TcpStream::connect(addr).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    (
+        TcpCommunicator::new(server, framing).unwrap(),
+        TcpCommunicator::new(client, framing).unwrap(),
+    )
+}
+
+#[test]
+fn tcp_length_prefixed_tests() {
+    let (tcp_server, tcp_client) = prepare_tcp_communicators(Framing::LengthPrefixed);
+    let tcp_server = Arc::new(Mutex::new(tcp_server));
+    let tcp_client = Arc::new(Mutex::new(tcp_client));
+
+    tests_base(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
+
+    ping_binary(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
+    ping_binary(Arc::clone(&tcp_client), Arc::clone(&tcp_server));
+}
+
 fn prepare_tcp_members() -> (TcpServer, TcpClient) {
     let (s_tx, s_rx) = channel();
 
@@ -238,18 +459,38 @@ fn prepare_tcp_members() -> (TcpServer, TcpClient) {
         s_tx.send(tcp_server).unwrap();
     });
 
-    thread::sleep(time::Duration::from_millis(100));
-
     let (c_tx, c_rx) = channel();
 
     thread::spawn(move || {
-        let tcp_client = TcpClient::new("0.0.0.0").unwrap();
+        let tcp_client = TcpClient::builder("0.0.0.0")
+            .retry(RetryPolicy::default())
+            .build()
+            .unwrap();
         c_tx.send(tcp_client).unwrap();
     });
 
     (s_rx.recv().unwrap(), c_rx.recv().unwrap())
 }
 
+fn prepare_tcp_members_with(
+    server_builder: TcpServerBuilder,
+    client_builder: TcpClientBuilder,
+) -> (TcpServer, TcpClient) {
+    let (s_tx, s_rx) = channel();
+
+    thread::spawn(move || {
+        let tcp_server = server_builder.build().unwrap();
+        s_tx.send(tcp_server).unwrap();
+    });
+
+    let tcp_client = client_builder
+        .retry(RetryPolicy::default())
+        .build()
+        .unwrap();
+
+    (s_rx.recv().unwrap(), tcp_client)
+}
+
 #[test]
 fn tcp_tests() {
     let (tcp_server, tcp_client) = prepare_tcp_members();
@@ -258,3 +499,1720 @@ fn tcp_tests() {
 
     tests_base(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
 }
+
+#[test]
+fn tcp_builder_tests() {
+    // 同じマシンで別々のポートを使って2組同時に動かせる
+    let pairs = [10010, 10011]
+        .into_iter()
+        .map(|port| {
+            thread::spawn(move || {
+                prepare_tcp_members_with(
+                    TcpServer::builder()
+                        .port(port)
+                        .reuse_address(true)
+                        .framing(Framing::EscapedLine),
+                    TcpClient::builder("127.0.0.1")
+                        .port(port)
+                        .framing(Framing::EscapedLine),
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for pair in pairs {
+        let (tcp_server, tcp_client) = pair.join().unwrap();
+        let tcp_server = Arc::new(Mutex::new(tcp_server));
+        let tcp_client = Arc::new(Mutex::new(tcp_client));
+
+        tests_base(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
+    }
+}
+
+#[test]
+fn tcp_builder_ipv6_tests() {
+    let (tcp_server, tcp_client) = prepare_tcp_members_with(
+        TcpServer::builder().port(10012).ip_version(IpVersion::V6),
+        TcpClient::builder("::1")
+            .port(10012)
+            .ip_version(IpVersion::V6),
+    );
+    let tcp_server = Arc::new(Mutex::new(tcp_server));
+    let tcp_client = Arc::new(Mutex::new(tcp_client));
+
+    ping(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
+    ping(Arc::clone(&tcp_client), Arc::clone(&tcp_server));
+}
+
+#[test]
+fn tcp_client_builder_rejects_mismatched_ip_version() {
+    let result = TcpClient::builder("127.0.0.1")
+        .port(10013)
+        .ip_version(IpVersion::V6)
+        .build();
+    assert!(result.is_err());
+}
+
+#[test]
+fn tcp_multi_session_tests() {
+    let listener = TcpServer::builder().port(0).listen().unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let clients = (0..3)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut tcp_client = TcpClient::builder("127.0.0.1")
+                    .port(addr.port())
+                    .build()
+                    .unwrap();
+                let message = format!("school {}", i);
+                tcp_client.send(message.as_bytes()).unwrap();
+                assert_eq!(tcp_client.receive().unwrap(), message.as_bytes());
+
+                let matrix = vec![vec![i as f64, 1.0], vec![2.0, 3.0]];
+                tcp_client.send_table(matrix.clone()).unwrap();
+                assert_eq!(tcp_client.receive_table().unwrap(), matrix);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut ids = Vec::new();
+    let handlers = listener
+        .incoming()
+        .take(3)
+        .map(|session| {
+            let mut session = session.unwrap();
+            assert!(session.peer_addr.ip().is_loopback());
+            ids.push(session.id);
+
+            thread::spawn(move || {
+                let data = session.server.receive().unwrap();
+                session.server.send(&data).unwrap();
+                let table = session.server.receive_table().unwrap();
+                session.server.send_table(table).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![0, 1, 2]);
+
+    for handle in clients.into_iter().chain(handlers) {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn channel_timeout_tests() {
+    let (s_tx, s_rx) = bounded(1);
+    let (c_tx, c_rx) = unbounded();
+
+    let mut channel_server = ChannelServer::new(s_rx, c_tx);
+    let mut channel_client = ChannelClient::new(c_rx, s_tx);
+    channel_server
+        .get_mut()
+        .set_read_timeout(Some(time::Duration::from_millis(50)));
+    channel_client
+        .get_mut()
+        .set_write_timeout(Some(time::Duration::from_millis(50)));
+
+    let err = channel_server.receive().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    let err = channel_server.receive_table().unwrap_err();
+    assert!(is_timeout(&err));
+
+    // 容量1のチャネルなので2通目は詰まる
+    channel_client.send(b"ping").unwrap();
+    let err = channel_client.send(b"ping").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+    assert_eq!(channel_server.receive().unwrap(), b"ping");
+}
+
+#[test]
+fn tcp_timeout_tests() {
+    let listener = TcpServer::builder()
+        .port(0)
+        .read_timeout(time::Duration::from_millis(100))
+        .listen()
+        .unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut tcp_client = TcpClient::builder("127.0.0.1")
+        .port(port)
+        .read_timeout(time::Duration::from_millis(100))
+        .build()
+        .unwrap();
+    let mut tcp_server = listener.accept().unwrap().server;
+
+    let err = tcp_server.receive().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    let err = tcp_client.receive_table().unwrap_err();
+    assert!(is_timeout(&err));
+
+    // タイムアウトした後も通信は続けられる
+    tcp_client.send(b"ping").unwrap();
+    assert_eq!(tcp_server.receive().unwrap(), b"ping");
+}
+
+#[test]
+fn tcp_timeout_mid_frame_tests() {
+    use std::io::Write;
+
+    // フレームの途中でタイムアウトしても、次の受信で続きから読める
+    let cases: [(Framing, &[&[u8]]); 3] = [
+        (Framing::Line, &[b"hel", b"lo\n"]),
+        (Framing::EscapedLine, &[b"hel", b"lo\n"]),
+        (
+            Framing::LengthPrefixed,
+            &[&[0, 0], &[0, 5, b'h', b'e'], b"llo"],
+        ),
+    ];
+    for (framing, chunks) in cases {
+        let listener = TcpServer::builder()
+            .port(0)
+            .framing(framing)
+            .read_timeout(time::Duration::from_millis(100))
+            .listen()
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut tcp_server = listener.accept().unwrap().server;
+
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            peer.write_all(chunk).unwrap();
+            let err = tcp_server.receive().unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        }
+        peer.write_all(last).unwrap();
+        assert_eq!(tcp_server.receive().unwrap(), b"hello");
+    }
+}
+
+#[test]
+fn tcp_retry_tests() {
+    // クライアントを先に起動してもサーバーが立ち上がれば繋がる
+    let client = thread::spawn(|| {
+        TcpClient::builder("127.0.0.1")
+            .port(10014)
+            .retry(RetryPolicy {
+                initial_delay: time::Duration::from_millis(50),
+                ..Default::default()
+            })
+            .build()
+            .unwrap()
+    });
+
+    thread::sleep(time::Duration::from_millis(300));
+    let tcp_server = TcpServer::builder().port(10014).build().unwrap();
+    let tcp_client = client.join().unwrap();
+
+    let tcp_server = Arc::new(Mutex::new(tcp_server));
+    let tcp_client = Arc::new(Mutex::new(tcp_client));
+    ping(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
+    ping(Arc::clone(&tcp_client), Arc::clone(&tcp_server));
+}
+
+#[test]
+fn tcp_retry_deadline_tests() {
+    let start = time::Instant::now();
+    let result = TcpClient::builder("127.0.0.1")
+        .port(10015)
+        .retry(RetryPolicy {
+            initial_delay: time::Duration::from_millis(20),
+            max_delay: time::Duration::from_millis(100),
+            multiplier: 2.0,
+            deadline: time::Duration::from_millis(300),
+        })
+        .build();
+
+    let err = result.err().unwrap();
+    assert!(err.to_string().contains("attempts"));
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+
+    // 倍率が大きすぎても間隔は上限で止まる
+    let start = time::Instant::now();
+    let result = TcpClient::builder("127.0.0.1")
+        .port(10015)
+        .retry(RetryPolicy {
+            initial_delay: time::Duration::from_millis(20),
+            max_delay: time::Duration::from_millis(100),
+            multiplier: f64::MAX,
+            deadline: time::Duration::from_millis(300),
+        })
+        .build();
+    assert!(result.is_err());
+    assert!(start.elapsed() < time::Duration::from_secs(2));
+
+    for multiplier in [-1.0, f64::NAN, f64::INFINITY] {
+        let result = TcpClient::builder("127.0.0.1")
+            .port(10015)
+            .retry(RetryPolicy {
+                multiplier,
+                ..RetryPolicy::default()
+            })
+            .build();
+        let err = result.err().unwrap();
+        assert!(err.to_string().contains("multiplier"));
+    }
+}
+
+#[test]
+fn matrix_tests() {
+    let m = Matrix::new(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+    assert_eq!(m.shape(), (2, 3));
+    assert_eq!(m[(1, 0)], 4.0);
+    assert_eq!(m.row(1), &[4.0, 5.0, 6.0]);
+    assert_eq!(m.get(2, 0), None);
+    assert_eq!(
+        m.clone().into_rows(),
+        vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]
+    );
+    assert_eq!(Matrix::from_rows(m.to_rows()).unwrap(), m);
+
+    let mut m = Matrix::from_fn(2, 2, |i, j| (i * 2 + j) as f64).unwrap();
+    m[(0, 1)] = 10.0;
+    assert_eq!(m.as_slice(), &[0.0, 10.0, 2.0, 3.0]);
+
+    assert_eq!(
+        Matrix::new(2, 2, vec![1.0, 2.0, 3.0]),
+        Err(MatrixError::DataLength {
+            rows: 2,
+            cols: 2,
+            len: 3
+        })
+    );
+    assert_eq!(Matrix::<f64>::from_rows(vec![]), Err(MatrixError::Empty));
+    assert_eq!(
+        Matrix::<f64>::from_rows(vec![vec![]]),
+        Err(MatrixError::Empty)
+    );
+    assert_eq!(
+        Matrix::from_rows(vec![vec![1.0, 2.0], vec![3.0]]),
+        Err(MatrixError::Ragged {
+            row: 1,
+            expected: 2,
+            found: 1
+        })
+    );
+
+    // 要素数が桁あふれする形は、相手から受け取った場合もエラーになる
+    assert_eq!(
+        Matrix::<f64>::new(usize::MAX, 2, vec![]),
+        Err(MatrixError::TooLarge {
+            rows: usize::MAX,
+            cols: 2
+        })
+    );
+    assert!(Matrix::from_fn(usize::MAX, 2, |_, _| 0.0).is_err());
+    for codec in [Codec::Json, Codec::Bincode] {
+        let data = codec
+            .encode(&(usize::MAX, 2usize, Vec::<f64>::new()))
+            .unwrap();
+        assert!(codec.decode::<Matrix>(&data).is_err());
+    }
+}
+
+#[test]
+fn matrix_validation_tests() {
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+
+    let mut channel_server = ChannelServer::new(s_rx, c_tx);
+    let mut channel_client = ChannelClient::new(c_rx, s_tx);
+
+    let err = channel_server
+        .send_table(vec![vec![1.0, 2.0], vec![3.0]])
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<MatrixError>(),
+        Some(MatrixError::Ragged { row: 1, .. })
+    ));
+    let err = channel_server.send_table(vec![]).unwrap_err();
+    assert_eq!(err.downcast_ref::<MatrixError>(), Some(&MatrixError::Empty));
+
+    // 相手が長方形でない表を送ってきた場合も受信側で弾く
+    channel_server
+        .send(br#"{"data":[["1e0","2e0"],["3e0"]]}"#)
+        .unwrap();
+    let err = channel_client.receive_table().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<MatrixError>(),
+        Some(MatrixError::Ragged { .. })
+    ));
+
+    let m = Matrix::new(3, 1, vec![1.0, -2.5, 3e-10]).unwrap();
+    channel_server.send_matrix(&m).unwrap();
+    assert_eq!(channel_client.receive_matrix().unwrap(), m);
+
+    // send_matrixとreceive_tableは同じ形式でやりとりする
+    channel_client.send_matrix(&m).unwrap();
+    assert_eq!(channel_server.receive_table().unwrap(), m.to_rows());
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct Parameters {
+    label: String,
+    modulus: u64,
+    weights: Vec<f64>,
+    public_key: Option<Vec<u8>>,
+}
+
+fn sample_parameters() -> Parameters {
+    Parameters {
+        label: "中学校A\nclass 1".to_string(),
+        modulus: u64::MAX - 58,
+        weights: vec![0.5, -1.25, 1e-3],
+        public_key: Some(vec![0, 1, 2, 255]),
+    }
+}
+
+#[test]
+fn value_tests() {
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+
+    let mut channel_server = ChannelServer::with_framing(s_rx, c_tx, Framing::EscapedLine);
+    let mut channel_client = ChannelClient::with_framing(c_rx, s_tx, Framing::EscapedLine);
+
+    let params = sample_parameters();
+    channel_server.send_value(&params).unwrap();
+    assert_eq!(
+        channel_client.receive_value::<Parameters>().unwrap(),
+        params
+    );
+
+    channel_client.send_value(&(42u32, "label")).unwrap();
+    let (n, label): (u32, String) = channel_server.receive_value().unwrap();
+    assert_eq!((n, label.as_str()), (42, "label"));
+
+    channel_server.send_value("not parameters").unwrap();
+    assert!(channel_client.receive_value::<Parameters>().is_err());
+}
+
+#[test]
+fn line_value_tests() {
+    // 既定のFraming::LineでもJSONの文字列中の改行やバックスラッシュが壊れない
+    let (mut tcp_server, mut tcp_client) = prepare_tcp_communicators(Framing::Line);
+
+    let params = sample_parameters();
+    tcp_server.send_value(&params).unwrap();
+    assert_eq!(tcp_client.receive_value::<Parameters>().unwrap(), params);
+
+    let text = "a\nb\\nc\r\\\\";
+    tcp_client.send_value(text).unwrap();
+    assert_eq!(tcp_server.receive_value::<String>().unwrap(), text);
+}
+
+#[test]
+fn tcp_value_tests() {
+    let (tcp_server, tcp_client) = prepare_tcp_communicators(Framing::LengthPrefixed);
+    let tcp_server = Arc::new(Mutex::new(tcp_server));
+    let tcp_client = Arc::new(Mutex::new(tcp_client));
+
+    let params = sample_parameters();
+    let c1 = Arc::clone(&tcp_server);
+    let p = params.clone();
+    thread::spawn(move || {
+        c1.lock().unwrap().send_value(&p).unwrap();
+    });
+
+    let received: Parameters = tcp_client.lock().unwrap().receive_value().unwrap();
+    assert_eq!(received, params);
+}
+
+const CODECS: [Codec; 4] = [Codec::Json, Codec::MessagePack, Codec::Cbor, Codec::Bincode];
+
+fn prepare_channel_members_with_codec(codec: Codec) -> (ChannelServer, ChannelClient) {
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+
+    let mut channel_server = ChannelServer::with_framing(s_rx, c_tx, Framing::LengthPrefixed);
+    let mut channel_client = ChannelClient::with_framing(c_rx, s_tx, Framing::LengthPrefixed);
+    channel_server.set_codec(codec).unwrap();
+    channel_client.set_codec(codec).unwrap();
+
+    (channel_server, channel_client)
+}
+
+#[test]
+fn codec_tests() {
+    for codec in CODECS {
+        let (channel_server, channel_client) = prepare_channel_members_with_codec(codec);
+        let channel_server = Arc::new(Mutex::new(channel_server));
+        let channel_client = Arc::new(Mutex::new(channel_client));
+
+        tests_base(Arc::clone(&channel_server), Arc::clone(&channel_client));
+
+        let mut channel_server = channel_server.lock().unwrap();
+        let mut channel_client = channel_client.lock().unwrap();
+
+        let params = sample_parameters();
+        channel_server.send_value(&params).unwrap();
+        assert_eq!(
+            channel_client.receive_value::<Parameters>().unwrap(),
+            params
+        );
+
+        let m = Matrix::new(2, 2, vec![f64::MAX, f64::MIN_POSITIVE, -0.0, 1.0 / 3.0]).unwrap();
+        channel_client.send_matrix(&m).unwrap();
+        assert_eq!(channel_server.receive_matrix().unwrap(), m);
+    }
+}
+
+#[test]
+fn codec_wire_format_tests() {
+    let table = vec![vec![1.0, 2.5]];
+
+    // JSONは従来通り数値を文字列にした形式のまま
+    let json = matrix::encode_table(Codec::Json, table.clone()).unwrap();
+    assert_eq!(json, br#"{"data":[["1e0","2.5e0"]]}"#);
+
+    let big = (0..100)
+        .map(|i| (0..100).map(|j| (i * j) as f64 / 7.0).collect())
+        .collect::<Vec<Vec<f64>>>();
+    let json_len = matrix::encode_table(Codec::Json, big.clone())
+        .unwrap()
+        .len();
+    for codec in [Codec::MessagePack, Codec::Cbor, Codec::Bincode] {
+        let data = matrix::encode_table(codec, big.clone()).unwrap();
+        assert!(
+            data.len() < json_len,
+            "{:?} is not smaller than JSON",
+            codec
+        );
+        assert_eq!(matrix::decode_table(codec, &data).unwrap(), big);
+    }
+
+    // バイナリ形式でも形が合わないものは弾く
+    #[derive(serde::Serialize)]
+    struct Broken {
+        rows: usize,
+        cols: usize,
+        data: Vec<f64>,
+    }
+    let broken = Broken {
+        rows: 2,
+        cols: 2,
+        data: vec![1.0, 2.0, 3.0],
+    };
+    for codec in [Codec::MessagePack, Codec::Cbor, Codec::Bincode] {
+        let data = codec.encode(&broken).unwrap();
+        assert!(matrix::decode_table(codec, &data).is_err());
+    }
+}
+
+#[test]
+fn negotiate_codec_tests() {
+    let (mut channel_server, mut channel_client) =
+        prepare_channel_members_with_codec(Codec::default());
+
+    let t = thread::spawn(move || {
+        let codec = negotiate_codec(&mut channel_client, &[Codec::Json, Codec::Cbor]).unwrap();
+        (channel_client, codec)
+    });
+    let codec = negotiate_codec(&mut channel_server, &CODECS).unwrap();
+    let (mut channel_client, client_codec) = t.join().unwrap();
+
+    assert_eq!(codec, Codec::Cbor);
+    assert_eq!(client_codec, Codec::Cbor);
+    assert_eq!(channel_server.codec(), Codec::Cbor);
+
+    channel_client.send_table(vec![vec![1.0, 2.0]]).unwrap();
+    assert_eq!(
+        channel_server.receive_table().unwrap(),
+        vec![vec![1.0, 2.0]]
+    );
+}
+
+#[test]
+fn codec_framing_tests() {
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+    let mut channel_server = ChannelServer::new(s_rx, c_tx);
+    let mut channel_client = ChannelClient::new(c_rx, s_tx);
+
+    // バイナリのコーデックはFraming::Lineでは使えない
+    for codec in [Codec::MessagePack, Codec::Cbor, Codec::Bincode] {
+        let err = channel_server.set_codec(codec).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(channel_server.codec(), Codec::Json);
+    }
+
+    // ネゴシエーションでもFraming::Lineで送れるJSONが選ばれる
+    let t = thread::spawn(move || {
+        let codec = negotiate_codec(&mut channel_client, &CODECS).unwrap();
+        (channel_client, codec)
+    });
+    let codec = negotiate_codec(&mut channel_server, &CODECS).unwrap();
+    let (mut channel_client, client_codec) = t.join().unwrap();
+    assert_eq!((codec, client_codec), (Codec::Json, Codec::Json));
+
+    channel_client.send_table(vec![vec![1.0, 2.0]]).unwrap();
+    assert_eq!(
+        channel_server.receive_table().unwrap(),
+        vec![vec![1.0, 2.0]]
+    );
+
+    let listener = TcpServer::builder().port(0).listen().unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let result = TcpClient::builder("127.0.0.1")
+        .port(port)
+        .codec(Codec::Bincode)
+        .build();
+    assert!(result.is_err());
+}
+
+#[test]
+fn tcp_codec_tests() {
+    let listener = TcpServer::builder()
+        .port(0)
+        .framing(Framing::LengthPrefixed)
+        .codec(Codec::MessagePack)
+        .listen()
+        .unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let tcp_client = TcpClient::builder("127.0.0.1")
+        .port(port)
+        .framing(Framing::LengthPrefixed)
+        .codec(Codec::MessagePack)
+        .build()
+        .unwrap();
+    let tcp_server = listener.accept().unwrap().server;
+    assert_eq!(tcp_server.codec(), Codec::MessagePack);
+
+    let tcp_server = Arc::new(Mutex::new(tcp_server));
+    let tcp_client = Arc::new(Mutex::new(tcp_client));
+    tests_base(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
+}
+
+fn ping_typed_matrix<T>(codec: Codec, matrix: Matrix<T>)
+where
+    T: Element + PartialEq + std::fmt::Debug,
+{
+    let (mut channel_server, mut channel_client) = prepare_channel_members_with_codec(codec);
+
+    channel_server.send_matrix(&matrix).unwrap();
+    assert_eq!(channel_client.receive_matrix::<T>().unwrap(), matrix);
+}
+
+#[test]
+fn integer_matrix_tests() {
+    // 2^53を超える値もf64を経由しないので正確に送れる
+    let p = (1u64 << 61) - 1;
+    for codec in CODECS {
+        ping_typed_matrix(
+            codec,
+            Matrix::new(1, 3, vec![i64::MIN, -1, i64::MAX]).unwrap(),
+        );
+        ping_typed_matrix(codec, Matrix::new(2, 1, vec![p, u64::MAX]).unwrap());
+        ping_typed_matrix(
+            codec,
+            Matrix::new(1, 2, vec![i128::MIN, i128::MAX]).unwrap(),
+        );
+
+        let big: BigInt = "-123456789012345678901234567890123456789012345678901234567890"
+            .parse()
+            .unwrap();
+        ping_typed_matrix(
+            codec,
+            Matrix::new(1, 2, vec![big, BigInt::from(0)]).unwrap(),
+        );
+    }
+}
+
+#[test]
+fn matrix_type_mismatch_tests() {
+    for codec in CODECS {
+        let (mut channel_server, mut channel_client) = prepare_channel_members_with_codec(codec);
+
+        channel_server
+            .send_matrix(&Matrix::new(1, 1, vec![-5i64]).unwrap())
+            .unwrap();
+        let err = channel_client.receive_matrix::<u64>().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<MatrixError>(),
+            Some(&MatrixError::TypeMismatch {
+                expected: "u64",
+                found: "i64".to_string()
+            }),
+            "{:?}",
+            codec
+        );
+
+        channel_server.send_table(vec![vec![1.0]]).unwrap();
+        let err = channel_client.receive_matrix::<i128>().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MatrixError>(),
+            Some(MatrixError::TypeMismatch { .. })
+        ));
+
+        channel_server
+            .send_matrix(&Matrix::new(1, 1, vec![BigInt::from(7)]).unwrap())
+            .unwrap();
+        let err = channel_client.receive_table().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<MatrixError>(),
+            Some(MatrixError::TypeMismatch { .. })
+        ));
+    }
+
+    // JSONでは整数の表に型タグが付き、f64の表は従来通りタグなし
+    let json = matrix::encode_matrix(Codec::Json, &Matrix::new(1, 2, vec![1u64, 2]).unwrap());
+    assert_eq!(json.unwrap(), br#"{"type":"u64","data":[["1","2"]]}"#);
+    let table: Matrix = matrix::decode_matrix(Codec::Json, br#"{"data":[["1E+00"]]}"#).unwrap();
+    assert_eq!(table.as_slice(), &[1.0]);
+}
+
+#[test]
+fn sharing_tests() {
+    let mut rng = rand::thread_rng();
+    let field = Field::default();
+
+    let scores = Matrix::new(2, 3, vec![80, -3, 0, 100, 1 << 59, -7]).unwrap();
+    let secret = field.encode_matrix(&scores);
+    let (a, b) = sharing::split(&secret, field, &mut rng).unwrap();
+    assert_ne!(a.values(), &secret);
+    assert_eq!(sharing::reconstruct(&a, &b).unwrap(), secret);
+    assert_eq!(field.decode_matrix(&secret), scores);
+
+    // シェアのまま線形演算ができる
+    let other = field.encode_matrix(&Matrix::new(2, 3, vec![1, 2, 3, 4, 5, 6]).unwrap());
+    let (c, d) = sharing::split(&other, field, &mut rng).unwrap();
+    let sum = sharing::reconstruct(&a.add(&c).unwrap(), &b.add(&d).unwrap()).unwrap();
+    assert_eq!(sum, field.add_matrix(&secret, &other).unwrap());
+    let diff = sharing::reconstruct(&a.sub(&c).unwrap(), &b.sub(&d).unwrap()).unwrap();
+    assert_eq!(diff, field.sub_matrix(&secret, &other).unwrap());
+
+    let scaled = sharing::reconstruct(&a.scalar_mul(3), &b.scalar_mul(3)).unwrap();
+    assert_eq!(field.decode_matrix(&scaled).as_slice()[..3], [240, -9, 0]);
+
+    let shifted = sharing::reconstruct(
+        &a.add_public(&other, Party::Server).unwrap(),
+        &b.add_public(&other, Party::Client).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(shifted, sum);
+
+    // 形や体が異なるシェアは混ぜられない
+    let (e, _) = sharing::split(
+        &field.encode_matrix(&Matrix::new(3, 2, vec![0; 6]).unwrap()),
+        field,
+        &mut rng,
+    )
+    .unwrap();
+    assert!(a.add(&e).is_err());
+    let small = Field::new(65521).unwrap();
+    let (f, _) = sharing::split(&Matrix::new(2, 3, vec![1; 6]).unwrap(), small, &mut rng).unwrap();
+    assert!(a.add(&f).is_err());
+    assert!(Share::new(small, Matrix::new(1, 1, vec![65521]).unwrap()).is_err());
+
+    assert!(Field::new(65521).is_ok());
+    assert!(Field::new(65535).is_err());
+    assert!(Field::new(1).is_err());
+    assert!(Field::new(u64::MAX - 58).is_err());
+
+    // 受け取った法も検証する
+    let json = Codec::Json.encode(&Field::new(65521).unwrap()).unwrap();
+    assert_eq!(json, b"65521");
+    assert_eq!(Codec::Json.decode::<Field>(&json).unwrap().modulus(), 65521);
+    for modulus in [0u64, 65535, u64::MAX - 58] {
+        let data = Codec::Json.encode(&modulus).unwrap();
+        assert!(Codec::Json.decode::<Field>(&data).is_err());
+    }
+}
+
+#[test]
+fn share_exchange_tests() {
+    for codec in CODECS {
+        let (channel_server, channel_client) = prepare_channel_members_with_codec(codec);
+        let field = Field::default();
+        let secret = field.encode_matrix(&Matrix::new(2, 2, vec![90, 75, -1, 60]).unwrap());
+
+        // 予備校側が秘密を分散し、双方で復元する
+        let s = secret.clone();
+        let handle = thread::spawn(move || {
+            let mut channel_server = channel_server;
+            let mine =
+                sharing::share_with_peer(&mut channel_server, &s, field, &mut rand::thread_rng())
+                    .unwrap();
+            sharing::open(&mut channel_server, &mine).unwrap()
+        });
+
+        let mut channel_client = channel_client;
+        let theirs = sharing::receive_share(&mut channel_client, field).unwrap();
+        assert_eq!(sharing::open(&mut channel_client, &theirs).unwrap(), secret);
+        assert_eq!(handle.join().unwrap(), secret);
+    }
+
+    let (mut channel_server, mut channel_client) = prepare_channel_members_with_codec(Codec::Json);
+    let small = Field::new(65521).unwrap();
+    let share = Share::new(small, Matrix::new(1, 1, vec![1]).unwrap()).unwrap();
+    sharing::send_share(&mut channel_server, &share).unwrap();
+    assert!(sharing::receive_share(&mut channel_client, Field::default()).is_err());
+
+    // 体の外の値が送られてきた場合もエラー
+    let forged = ForgedShare {
+        field: 65521,
+        values: Matrix::new(1, 1, vec![70000]).unwrap(),
+    };
+    channel_server.send_value(&forged).unwrap();
+    assert!(sharing::receive_share(&mut channel_client, small).is_err());
+
+    // 受信関数を通さずにデシリアライズしても検証される
+    for codec in CODECS {
+        let data = codec.encode(&forged).unwrap();
+        assert!(codec.decode::<Share>(&data).is_err());
+
+        let valid = ForgedShare {
+            field: 65521,
+            values: Matrix::new(1, 2, vec![0, 65520]).unwrap(),
+        };
+        let data = codec.encode(&valid).unwrap();
+        let share = codec.decode::<Share>(&data).unwrap();
+        assert_eq!(share.values(), &valid.values);
+    }
+}
+
+// 検証を通さずにシェアと同じ形で送るための型
+#[derive(serde::Serialize)]
+struct ForgedShare {
+    field: u64,
+    values: Matrix<u64>,
+}
+
+fn plain_product(a: &Matrix, b: &Matrix) -> Matrix {
+    Matrix::from_fn(a.rows(), b.cols(), |i, j| {
+        (0..a.cols()).map(|k| a[(i, k)] * b[(k, j)]).sum()
+    })
+    .unwrap()
+}
+
+fn assert_close(actual: &Matrix, expected: &Matrix) {
+    assert_eq!(actual.shape(), expected.shape());
+    for (x, y) in actual.as_slice().iter().zip(expected.as_slice()) {
+        assert!((x - y).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+    }
+}
+
+fn sample_product_inputs() -> (Matrix, Matrix) {
+    // 生徒ごとの点数と科目ごとの重み
+    let a = Matrix::from_rows(vec![
+        vec![80.0, 65.0, 90.0],
+        vec![55.0, 100.0, 72.5],
+        vec![0.0, 40.0, 61.0],
+        vec![99.0, 12.0, 33.0],
+    ])
+    .unwrap();
+    let b = Matrix::from_rows(vec![vec![0.5, 1.0], vec![-0.25, 2.0], vec![1.5, 0.0]]).unwrap();
+
+    (a, b)
+}
+
+#[test]
+fn product_tests() {
+    let (a, b) = sample_product_inputs();
+    let (channel_server, channel_client) = prepare_channel_members_with_codec(Codec::Json);
+
+    let a2 = a.clone();
+    let handle = thread::spawn(move || {
+        let mut channel_server = channel_server;
+        let share =
+            product::server_multiply(&mut channel_server, &a2, &mut rand::thread_rng()).unwrap();
+        product::reveal(&mut channel_server, &share).unwrap()
+    });
+
+    let mut channel_client = channel_client;
+    let share = product::client_multiply(&mut channel_client, &b).unwrap();
+    let revealed = product::reveal(&mut channel_client, &share).unwrap();
+
+    let expected = plain_product(&a, &b);
+    assert_close(&revealed, &expected);
+    assert_close(&handle.join().unwrap(), &expected);
+}
+
+#[test]
+fn product_with_dealer_tests() {
+    let (a, b) = sample_product_inputs();
+    let (channel_server, channel_client) = prepare_channel_members_with_codec(Codec::Json);
+    let (dealer_to_server, server_to_dealer) = prepare_channel_members_with_codec(Codec::Json);
+    let (dealer_to_client, client_to_dealer) = prepare_channel_members_with_codec(Codec::Json);
+
+    let dealer = thread::spawn(move || {
+        let (mut s, mut c) = (dealer_to_server, dealer_to_client);
+        product::deal(&mut s, &mut c, &mut rand::thread_rng()).unwrap();
+    });
+
+    let a2 = a.clone();
+    let server = thread::spawn(move || {
+        let (mut comm, mut dealer) = (channel_server, server_to_dealer);
+        product::server_multiply_with_dealer(&mut comm, &mut dealer, &a2).unwrap()
+    });
+
+    let (mut comm, mut dealer_comm) = (channel_client, client_to_dealer);
+    let client_share =
+        product::client_multiply_with_dealer(&mut comm, &mut dealer_comm, &b).unwrap();
+    let server_share = server.join().unwrap();
+    dealer.join().unwrap();
+
+    let expected = plain_product(&a, &b);
+    let sum = Matrix::from_fn(4, 2, |i, j| server_share[(i, j)] + client_share[(i, j)]).unwrap();
+    assert_close(&sum, &expected);
+    // 片方のシェアだけでは積にならない
+    assert!((client_share[(0, 0)] - expected[(0, 0)]).abs() > 1e-6);
+}
+
+#[test]
+fn product_shape_mismatch_tests() {
+    let (a, _) = sample_product_inputs();
+    let (channel_server, mut channel_client) = prepare_channel_members_with_codec(Codec::Json);
+
+    let handle = thread::spawn(move || {
+        let mut channel_server = channel_server;
+        product::server_multiply(&mut channel_server, &a, &mut rand::thread_rng())
+    });
+
+    // 中学側の行数が合わない場合は双方エラーになる
+    let b = Matrix::new(2, 2, vec![1.0; 4]).unwrap();
+    assert!(product::client_multiply(&mut channel_client, &b).is_err());
+    drop(channel_client);
+    assert!(handle.join().unwrap().is_err());
+
+    let (mut channel_server, _channel_client) = prepare_channel_members_with_codec(Codec::Json);
+    let column = Matrix::new(3, 1, vec![1.0; 3]).unwrap();
+    assert!(
+        product::server_multiply(&mut channel_server, &column, &mut rand::thread_rng()).is_err()
+    );
+
+    // ディーラーは大きすぎる形を受け取ると確保せずにエラーを返す
+    // 個々の形が上限内でも、積の要素数や計算量が上限を超えればエラーになる
+    let shapes = [
+        ([1e300, 2.0], [2.0, 2.0]),
+        ([2.0, 5000.0], [5000.0, 2.0]),
+        ([2048.0, 1024.0], [1024.0, 2.0]),
+        ([2.0, 2.5], [2.5, 2.0]),
+        ([4096.0, 256.0], [256.0, 4096.0]),
+        ([1024.0, 1024.0], [1024.0, 1024.0]),
+    ];
+    for (server_shape, client_shape) in shapes {
+        let (mut dealer_to_server, mut server_to_dealer) =
+            prepare_channel_members_with_codec(Codec::Json);
+        let (mut dealer_to_client, mut client_to_dealer) =
+            prepare_channel_members_with_codec(Codec::Json);
+        server_to_dealer
+            .send_table(vec![server_shape.to_vec()])
+            .unwrap();
+        client_to_dealer
+            .send_table(vec![client_shape.to_vec()])
+            .unwrap();
+        assert!(product::deal(
+            &mut dealer_to_server,
+            &mut dealer_to_client,
+            &mut rand::thread_rng()
+        )
+        .is_err());
+    }
+}
+
+#[test]
+fn paillier_tests() {
+    let mut rng = rand::thread_rng();
+    let (public_key, private_key) = paillier::generate_keypair(512, &mut rng).unwrap();
+    assert!(public_key.bits() >= 511);
+    assert!(paillier::generate_keypair(64, &mut rng).is_err());
+
+    let a = public_key.encrypt(&BigInt::from(1234), &mut rng).unwrap();
+    let b = public_key.encrypt(&BigInt::from(-34), &mut rng).unwrap();
+    // 同じ平文でも暗号文は毎回異なる
+    assert_ne!(
+        a,
+        public_key.encrypt(&BigInt::from(1234), &mut rng).unwrap()
+    );
+    assert_eq!(private_key.decrypt(&a).unwrap(), BigInt::from(1234));
+    assert_eq!(private_key.decrypt(&b).unwrap(), BigInt::from(-34));
+
+    let sum = public_key.add(&a, &b);
+    assert_eq!(private_key.decrypt(&sum).unwrap(), BigInt::from(1200));
+    let shifted = public_key.add_plain(&a, &BigInt::from(-2000)).unwrap();
+    assert_eq!(private_key.decrypt(&shifted).unwrap(), BigInt::from(-766));
+    let scaled = public_key.mul_plain(&b, &BigInt::from(-3));
+    assert_eq!(private_key.decrypt(&scaled).unwrap(), BigInt::from(102));
+
+    let too_large = BigInt::from(public_key.n().clone());
+    assert!(public_key.encrypt(&too_large, &mut rng).is_err());
+}
+
+#[test]
+fn paillier_matrix_vector_tests() {
+    let mut rng = rand::thread_rng();
+    let (public_key, private_key) = paillier::generate_keypair(512, &mut rng).unwrap();
+    let (mut channel_server, mut channel_client) =
+        prepare_channel_members_with_codec(Codec::MessagePack);
+
+    // 中学側が鍵を作ってベクトルを暗号化し、予備校側が自分の行列を掛けて返す
+    let x = vec![3i64, -1, 4];
+    paillier::send_public_key(&mut channel_client, &public_key).unwrap();
+    let encrypted = public_key.encrypt_vector(&x, &mut rng).unwrap();
+    paillier::send_ciphertexts(&mut channel_client, &encrypted).unwrap();
+
+    let key = paillier::receive_public_key(&mut channel_server).unwrap();
+    assert_eq!(key, public_key);
+    let received = paillier::receive_ciphertexts(&mut channel_server, &key).unwrap();
+    let a = Matrix::from_rows(vec![vec![80i64, 65, 90], vec![-5, 0, 2]]).unwrap();
+    let product = key.matrix_vector_product(&a, &received).unwrap();
+    paillier::send_ciphertexts(&mut channel_server, &product).unwrap();
+
+    let result = paillier::receive_ciphertexts(&mut channel_client, &public_key).unwrap();
+    assert_eq!(
+        private_key.decrypt_vector(&result).unwrap(),
+        vec![BigInt::from(535), BigInt::from(-7)]
+    );
+
+    assert!(key.dot(&received, &[1i64, 2]).is_err());
+
+    // 鍵の範囲外の暗号文は受け取らない
+    let (other_key, _) = paillier::generate_keypair(256, &mut rng).unwrap();
+    paillier::send_ciphertexts(&mut channel_server, &product).unwrap();
+    assert!(paillier::receive_ciphertexts(&mut channel_client, &other_key).is_err());
+
+    // nと互いに素でない暗号文も受け取らず、直接復号してもパニックしない
+    let n = public_key.n().clone();
+    channel_server.send_value(&[&n]).unwrap();
+    assert!(paillier::receive_ciphertexts(&mut channel_client, &public_key).is_err());
+    let data = Codec::MessagePack.encode(&n).unwrap();
+    let forged: paillier::Ciphertext = Codec::MessagePack.decode(&data).unwrap();
+    assert!(private_key.decrypt(&forged).is_err());
+}
+
+#[test]
+fn beaver_tests() {
+    let field = Field::default();
+    let mut rng = rand::thread_rng();
+    let (channel_server, channel_client) = prepare_channel_members_with_codec(Codec::Bincode);
+    let (dealer_to_server, server_to_dealer) = prepare_channel_members_with_codec(Codec::Bincode);
+    let (dealer_to_client, client_to_dealer) = prepare_channel_members_with_codec(Codec::Bincode);
+
+    let dealer = thread::spawn(move || {
+        let (mut s, mut c) = (dealer_to_server, dealer_to_client);
+        let mut rng = rand::thread_rng();
+        beaver::deal(&mut s, &mut c, &mut rng).unwrap();
+        beaver::deal(&mut c, &mut s, &mut rng).unwrap();
+    });
+
+    let x = field.encode_matrix(&Matrix::new(2, 2, vec![3, -4, 5, 0]).unwrap());
+    let y = field.encode_matrix(&Matrix::new(2, 2, vec![7, 6, -2, 9]).unwrap());
+    let (x_s, x_c) = sharing::split(&x, field, &mut rng).unwrap();
+    let (y_s, y_c) = sharing::split(&y, field, &mut rng).unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut comm, mut dealer) = (channel_server, server_to_dealer);
+        let shape = TripleShape::Elementwise(2, 2);
+        let triples = beaver::request_triples(&mut dealer, Party::Server, field, shape, 1).unwrap();
+        let z = beaver::multiply(&mut comm, Party::Server, &x_s, &y_s, &triples[0]).unwrap();
+        let hadamard = sharing::open(&mut comm, &z).unwrap();
+
+        let shape = TripleShape::Matrix(2, 2, 2);
+        let triples = beaver::request_triples(&mut dealer, Party::Server, field, shape, 1).unwrap();
+        let z = beaver::matrix_multiply(&mut comm, Party::Server, &x_s, &y_s, &triples[0]);
+        let product = sharing::open(&mut comm, &z.unwrap()).unwrap();
+
+        (hadamard, product)
+    });
+
+    let (mut comm, mut dealer_comm) = (channel_client, client_to_dealer);
+    let shape = TripleShape::Elementwise(2, 2);
+    let triples =
+        beaver::request_triples(&mut dealer_comm, Party::Client, field, shape, 1).unwrap();
+    // 要素ごとの積のtripleは行列積には使えない
+    assert!(beaver::matrix_multiply(&mut comm, Party::Client, &x_c, &y_c, &triples[0]).is_err());
+    let z = beaver::multiply(&mut comm, Party::Client, &x_c, &y_c, &triples[0]).unwrap();
+    let hadamard = sharing::open(&mut comm, &z).unwrap();
+
+    let shape = TripleShape::Matrix(2, 2, 2);
+    let triples =
+        beaver::request_triples(&mut dealer_comm, Party::Client, field, shape, 1).unwrap();
+    let z = beaver::matrix_multiply(&mut comm, Party::Client, &x_c, &y_c, &triples[0]).unwrap();
+    let product = sharing::open(&mut comm, &z).unwrap();
+
+    dealer.join().unwrap();
+    assert_eq!((hadamard.clone(), product.clone()), server.join().unwrap());
+    assert_eq!(
+        field.decode_matrix(&hadamard),
+        Matrix::new(2, 2, vec![21, -24, -10, 0]).unwrap()
+    );
+    assert_eq!(
+        field.decode_matrix(&product),
+        Matrix::new(2, 2, vec![29, -18, 35, 30]).unwrap()
+    );
+}
+
+#[test]
+fn beaver_tcp_dealer_tests() {
+    let field = Field::new(65521).unwrap();
+    let listener = TcpServer::builder().port(0).listen().unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let dealer = thread::spawn(move || beaver::serve(&listener, &mut rand::thread_rng()));
+
+    let request = move |party| {
+        let mut tcp_client = TcpClient::builder("127.0.0.1").port(port).build().unwrap();
+        let shape = TripleShape::Matrix(1, 3, 2);
+        beaver::request_triples(&mut tcp_client, party, field, shape, 4).unwrap()
+    };
+    let server = thread::spawn(move || request(Party::Server));
+    let client_triples = request(Party::Client);
+    let server_triples = server.join().unwrap();
+    dealer.join().unwrap().unwrap();
+
+    assert_eq!(server_triples.len(), 4);
+    for (s, c) in server_triples.iter().zip(&client_triples) {
+        let a = sharing::reconstruct(&s.a, &c.a).unwrap();
+        let b = sharing::reconstruct(&s.b, &c.b).unwrap();
+        let c = sharing::reconstruct(&s.c, &c.c).unwrap();
+        assert_eq!(field.mul_matrix(&a, &b).unwrap(), c);
+    }
+
+    // 両者が同じ立場で要求した場合はディーラーがエラーを返す
+    let listener = TcpServer::builder().port(0).listen().unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let dealer = thread::spawn(move || beaver::serve(&listener, &mut rand::thread_rng()));
+    let clients = (0..2)
+        .map(|_| {
+            thread::spawn(move || {
+                let mut tcp_client = TcpClient::builder("127.0.0.1").port(port).build().unwrap();
+                let shape = TripleShape::Elementwise(1, 1);
+                beaver::request_triples(&mut tcp_client, Party::Server, field, shape, 1)
+            })
+        })
+        .collect::<Vec<_>>();
+    assert!(dealer.join().unwrap().is_err());
+    for client in clients {
+        assert!(client.join().unwrap().is_err());
+    }
+}
+
+#[test]
+fn beaver_dealer_rejects_requests_tests() {
+    // 不正な要求を受け取ってもディーラーは落ちずにエラーを返す
+    let request = |field: u64, shape: &str, count: usize| {
+        format!(
+            r#"{{"party":"Server","field":{},"shape":{},"count":{}}}"#,
+            field, shape, count
+        )
+    };
+    let cases = [
+        (request(65521, r#"{"Elementwise":[1,1]}"#, 1), true),
+        (request(0, r#"{"Elementwise":[1,1]}"#, 1), false),
+        (request(65535, r#"{"Elementwise":[1,1]}"#, 1), false),
+        (
+            request(65521, r#"{"Elementwise":[1,1]}"#, usize::MAX),
+            false,
+        ),
+        (
+            request(65521, &format!(r#"{{"Matrix":[{},2,2]}}"#, usize::MAX), 1),
+            false,
+        ),
+    ];
+    for (request, ok) in cases {
+        let (mut dealer_to_server, mut server_to_dealer) =
+            prepare_channel_members_with_codec(Codec::Json);
+        let (mut dealer_to_client, mut client_to_dealer) =
+            prepare_channel_members_with_codec(Codec::Json);
+        server_to_dealer.send(request.as_bytes()).unwrap();
+        client_to_dealer
+            .send(request.replace("Server", "Client").as_bytes())
+            .unwrap();
+
+        let mut rng = rand::thread_rng();
+        let result = beaver::deal(&mut dealer_to_server, &mut dealer_to_client, &mut rng);
+        assert_eq!(result.is_ok(), ok, "{}", request);
+    }
+
+    // 断られた要求は参加者側にも理由付きのエラーとして返る
+    let field = Field::new(65521).unwrap();
+    let shape = TripleShape::Elementwise(1, 1);
+    let (mut dealer_to_server, mut server_to_dealer) =
+        prepare_channel_members_with_codec(Codec::Bincode);
+    let (mut dealer_to_client, mut client_to_dealer) =
+        prepare_channel_members_with_codec(Codec::Bincode);
+    let dealer = thread::spawn(move || {
+        let result = beaver::deal(
+            &mut dealer_to_server,
+            &mut dealer_to_client,
+            &mut rand::thread_rng(),
+        );
+        // 切断によるエラーと区別するため、参加者の確認が終わるまで通信路を閉じない
+        (result, dealer_to_server, dealer_to_client)
+    });
+    let server = thread::spawn(move || {
+        beaver::request_triples(&mut server_to_dealer, Party::Server, field, shape, 1)
+    });
+    let client_result =
+        beaver::request_triples(&mut client_to_dealer, Party::Client, field, shape, 2);
+    let server_result = server.join().unwrap();
+    for result in [server_result, client_result] {
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("rejected"), "{}", err);
+    }
+    assert!(dealer.join().unwrap().0.is_err());
+
+    let field = Field::new(65521).unwrap();
+    let mut rng = rand::thread_rng();
+    let shape = TripleShape::Elementwise(1, 1);
+    assert!(beaver::generate_triples(field, shape, beaver::MAX_TRIPLES + 1, &mut rng).is_err());
+    let shape = TripleShape::Matrix(usize::MAX, 2, 2);
+    assert!(beaver::generate_triples(field, shape, 1, &mut rng).is_err());
+}
+
+#[test]
+fn prg_tests() {
+    let seed = prg::random_seed();
+    let mut p1 = Prg::from_seed(seed);
+    let mut p2 = Prg::from_seed(seed);
+    assert_eq!(p1.seed(), seed);
+
+    // 同じシードからは同じ行列が得られる
+    let m1 = p1.random_matrix(3, 4, 10.0).unwrap();
+    assert_eq!(m1, p2.random_matrix(3, 4, 10.0).unwrap());
+    assert!(m1.as_slice().iter().all(|x| (-10.0..10.0).contains(x)));
+    let field = Field::new(65521).unwrap();
+    let f1 = p1.random_field_matrix(field, 2, 2).unwrap();
+    assert_eq!(f1, p2.random_field_matrix(field, 2, 2).unwrap());
+    assert!(f1.as_slice().iter().all(|&x| field.contains(x)));
+
+    let mut s0 = Prg::with_stream(seed, 0);
+    let mut s1 = Prg::with_stream(seed, 1);
+    assert_ne!(
+        s0.random_matrix(2, 2, 1.0).unwrap(),
+        s1.random_matrix(2, 2, 1.0).unwrap()
+    );
+    assert_ne!(
+        Prg::from_entropy().random_matrix(2, 2, 1.0).unwrap(),
+        Prg::from_entropy().random_matrix(2, 2, 1.0).unwrap()
+    );
+
+    // 共通のシードから双方が同じシェアを作れる
+    let (channel_server, mut channel_client) = prepare_channel_members_with_codec(Codec::Json);
+    let handle = thread::spawn(move || {
+        let mut channel_server = channel_server;
+        prg::exchange_seed(&mut channel_server).unwrap()
+    });
+    let common = prg::exchange_seed(&mut channel_client).unwrap();
+    assert_eq!(common, handle.join().unwrap());
+
+    let secret = Matrix::new(1, 3, vec![1, 2, 3]).unwrap();
+    let (a, b) = sharing::split(&secret, field, &mut Prg::from_seed(common)).unwrap();
+    let (c, d) = sharing::split(&secret, field, &mut Prg::from_seed(common)).unwrap();
+    assert_eq!((a, b), (c, d));
+}
+
+// 送信したバイト数を数える
+struct CountingCommunicator<C> {
+    inner: C,
+    sent: usize,
+}
+
+impl<C: Communicator> Communicator for CountingCommunicator<C> {
+    fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.sent += data.len();
+        self.inner.send(data)
+    }
+
+    fn receive(&mut self) -> std::io::Result<Vec<u8>> {
+        self.inner.receive()
+    }
+}
+
+fn dealer_traffic(seeded: bool) -> usize {
+    let a = Matrix::from_fn(10, 10, |i, j| (i * 10 + j) as f64).unwrap();
+    let b = Matrix::from_fn(10, 10, |i, j| i as f64 - j as f64).unwrap();
+    let (channel_server, channel_client) = prepare_channel_members_with_codec(Codec::Json);
+    let (dealer_to_server, server_to_dealer) = prepare_channel_members_with_codec(Codec::Json);
+    let (dealer_to_client, client_to_dealer) = prepare_channel_members_with_codec(Codec::Json);
+
+    let dealer = thread::spawn(move || {
+        let mut s = CountingCommunicator {
+            inner: dealer_to_server,
+            sent: 0,
+        };
+        let mut c = CountingCommunicator {
+            inner: dealer_to_client,
+            sent: 0,
+        };
+        if seeded {
+            product::deal_seeded(&mut s, &mut c).unwrap();
+        } else {
+            product::deal(&mut s, &mut c, &mut rand::thread_rng()).unwrap();
+        }
+        s.sent + c.sent
+    });
+
+    let a2 = a.clone();
+    let server = thread::spawn(move || {
+        let (mut comm, mut dealer) = (channel_server, server_to_dealer);
+        if seeded {
+            product::server_multiply_with_seeded_dealer(&mut comm, &mut dealer, &a2).unwrap()
+        } else {
+            product::server_multiply_with_dealer(&mut comm, &mut dealer, &a2).unwrap()
+        }
+    });
+
+    let (mut comm, mut dealer_comm) = (channel_client, client_to_dealer);
+    let client_share = if seeded {
+        product::client_multiply_with_seeded_dealer(&mut comm, &mut dealer_comm, &b).unwrap()
+    } else {
+        product::client_multiply_with_dealer(&mut comm, &mut dealer_comm, &b).unwrap()
+    };
+    let server_share = server.join().unwrap();
+
+    let sum = Matrix::from_fn(10, 10, |i, j| server_share[(i, j)] + client_share[(i, j)]);
+    assert_close(&sum.unwrap(), &plain_product(&a, &b));
+
+    dealer.join().unwrap()
+}
+
+#[test]
+fn product_with_seeded_dealer_tests() {
+    let explicit = dealer_traffic(false);
+    let seeded = dealer_traffic(true);
+    assert!(seeded * 2 <= explicit, "{} vs {}", seeded, explicit);
+}
+
+#[test]
+fn encrypted_channel_tests() {
+    let key = PresharedKey::from_passphrase("correct horse battery staple");
+
+    // 中学側から予備校側への通信はテストから中継して、再送や入れ替えを試す
+    let (s_tx, s_rx) = unbounded();
+    let (c_tx, c_rx) = unbounded();
+    let (p_tx, p_rx) = unbounded::<Vec<u8>>();
+    let channel_server = ChannelServer::new(s_rx, c_tx);
+    let channel_client = ChannelClient::new(c_rx, p_tx);
+
+    let k = key.clone();
+    let server = thread::spawn(move || EncryptedCommunicator::new(channel_server, &k).unwrap());
+    let client = thread::spawn(move || EncryptedCommunicator::new(channel_client, &key).unwrap());
+    for _ in 0..2 {
+        s_tx.send(p_rx.recv().unwrap()).unwrap();
+    }
+    let mut server = server.join().unwrap();
+    let mut client = client.join().unwrap();
+
+    let secret = b"secret scores: 80, 65, 90";
+    client.send(secret).unwrap();
+    client.send(b"second").unwrap();
+    let f1 = p_rx.recv().unwrap();
+    let f2 = p_rx.recv().unwrap();
+    assert!(!f1.windows(secret.len()).any(|w| w == secret));
+
+    // 順序が入れ替わったフレームは受け取らない
+    s_tx.send(f2.clone()).unwrap();
+    let err = server.receive().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    s_tx.send(f1.clone()).unwrap();
+    assert_eq!(server.receive().unwrap(), secret);
+    s_tx.send(f2).unwrap();
+    assert_eq!(server.receive().unwrap(), b"second");
+
+    // 再送されたフレームも受け取らない
+    s_tx.send(f1).unwrap();
+    let err = server.receive().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // 改ざんされたフレームは認証に失敗する
+    client.send(b"third").unwrap();
+    let mut f3 = p_rx.recv().unwrap();
+    *f3.last_mut().unwrap() ^= 1;
+    s_tx.send(f3).unwrap();
+    let err = server.receive().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // 逆向きも暗号化されていて、表や値もそのまま送れる
+    server.send_table(vec![vec![1.0, 2.5]]).unwrap();
+    assert_eq!(client.receive_table().unwrap(), vec![vec![1.0, 2.5]]);
+}
+
+#[test]
+fn encrypted_tcp_tests() {
+    let key = PresharedKey::from_passphrase("lab passphrase");
+    let (tcp_server, tcp_client) = prepare_tcp_communicators(Framing::Line);
+
+    let k = key.clone();
+    let server = thread::spawn(move || EncryptedCommunicator::new(tcp_server, &k).unwrap());
+    let tcp_client = EncryptedCommunicator::new(tcp_client, &key).unwrap();
+    let tcp_server = server.join().unwrap();
+
+    let tcp_server = Arc::new(Mutex::new(tcp_server));
+    let tcp_client = Arc::new(Mutex::new(tcp_client));
+    tests_base(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
+
+    // 合言葉が異なる場合は双方で失敗する
+    let (tcp_server, tcp_client) = prepare_tcp_communicators(Framing::LengthPrefixed);
+    let server = thread::spawn(move || {
+        EncryptedCommunicator::new(tcp_server, &PresharedKey::from_bytes([1; 32])).is_err()
+    });
+    assert!(EncryptedCommunicator::new(tcp_client, &PresharedKey::from_bytes([2; 32])).is_err());
+    assert!(server.join().unwrap());
+}
+
+#[test]
+fn handshake_tcp_tests() {
+    let keypair = StaticKeypair::generate();
+    let server_key = keypair.public_key();
+    assert_eq!(
+        StaticKeypair::from_secret_bytes(keypair.secret_bytes()).public_key(),
+        server_key
+    );
+
+    let server = thread::spawn(move || {
+        TcpServer::builder()
+            .port(10016)
+            .build_encrypted(&Handshake::new().static_key(keypair))
+            .unwrap()
+    });
+    let tcp_client = TcpClient::builder("127.0.0.1")
+        .port(10016)
+        .retry(RetryPolicy::default())
+        .build_encrypted(&Handshake::new().peer_key(server_key))
+        .unwrap();
+    let tcp_server = server.join().unwrap();
+
+    let tcp_server = Arc::new(Mutex::new(tcp_server));
+    let tcp_client = Arc::new(Mutex::new(tcp_client));
+    tests_base(Arc::clone(&tcp_server), Arc::clone(&tcp_client));
+}
+
+// 予備校側と中学側の間に攻撃者が入り、双方とそれぞれ鍵交換する
+// 攻撃者のスレッドは、鍵交換に成功すれば中学側から読み取ったメッセージを返す
+type MitmResult = (
+    EncryptedCommunicator<ChannelServer>,
+    anyhow::Result<EncryptedCommunicator<ChannelClient>>,
+    thread::JoinHandle<Option<Vec<u8>>>,
+);
+
+fn handshake_through_mitm(
+    server_handshake: Handshake,
+    client_handshake: Handshake,
+    mitm_handshake: Handshake,
+) -> MitmResult {
+    let (channel_server, mitm_to_server) = prepare_channel_members_with_codec(Codec::Json);
+    let (mitm_to_client, channel_client) = prepare_channel_members_with_codec(Codec::Json);
+
+    let server = thread::spawn(move || {
+        EncryptedCommunicator::with_handshake(channel_server, &server_handshake).unwrap()
+    });
+    let mitm = thread::spawn(move || {
+        let mut upstream =
+            EncryptedCommunicator::with_handshake(mitm_to_server, &Handshake::new()).unwrap();
+        let mut downstream =
+            EncryptedCommunicator::with_handshake(mitm_to_client, &mitm_handshake).ok()?;
+
+        // 中学側からのメッセージを読んでから予備校側に転送する
+        let data = downstream.receive().unwrap();
+        upstream.send(&data).unwrap();
+        Some(data)
+    });
+    let client = EncryptedCommunicator::with_handshake(channel_client, &client_handshake);
+
+    (server.join().unwrap(), client, mitm)
+}
+
+#[test]
+fn handshake_mitm_tests() {
+    // 認証なしの鍵交換では、攻撃者が気付かれずに通信を読める
+    let (mut server, client, mitm) =
+        handshake_through_mitm(Handshake::new(), Handshake::new(), Handshake::new());
+    client.unwrap().send(b"scores").unwrap();
+    assert_eq!(server.receive().unwrap(), b"scores");
+    assert_eq!(mitm.join().unwrap().unwrap(), b"scores");
+
+    // 中学側が予備校側の公開鍵を知っていれば、攻撃者との鍵交換は失敗する
+    let keypair = StaticKeypair::generate();
+    let server_key = keypair.public_key();
+    let attacker = StaticKeypair::generate();
+    let (_server, client, mitm) = handshake_through_mitm(
+        Handshake::new().static_key(keypair.clone()),
+        Handshake::new().peer_key(server_key),
+        Handshake::new().static_key(attacker),
+    );
+    let err = client.err().unwrap();
+    assert!(err.to_string().contains("unexpected static key"), "{}", err);
+    assert!(mitm.join().unwrap().is_none());
+
+    let (_server, client, mitm) = handshake_through_mitm(
+        Handshake::new().static_key(keypair),
+        Handshake::new().peer_key(server_key),
+        Handshake::new(),
+    );
+    assert!(client.is_err());
+    assert!(mitm.join().unwrap().is_none());
+
+    // 相互に公開鍵を確認することもできる
+    let (channel_server, channel_client) = prepare_channel_members_with_codec(Codec::Json);
+    let server_keypair = StaticKeypair::generate();
+    let client_keypair = StaticKeypair::generate();
+    let server_handshake = Handshake::new()
+        .static_key(server_keypair.clone())
+        .peer_key(client_keypair.public_key());
+    let client_handshake = Handshake::new()
+        .static_key(client_keypair)
+        .peer_key(server_keypair.public_key());
+    let server = thread::spawn(move || {
+        EncryptedCommunicator::with_handshake(channel_server, &server_handshake).unwrap()
+    });
+    let mut client =
+        EncryptedCommunicator::with_handshake(channel_client, &client_handshake).unwrap();
+    let mut server = server.join().unwrap();
+    server.send_value(&sample_parameters()).unwrap();
+    assert_eq!(
+        client.receive_value::<Parameters>().unwrap(),
+        sample_parameters()
+    );
+}
+
+#[test]
+fn mitm_rules_tests() {
+    let rules = Rules::parse(
+        "# コメント\n\
+         set c2s 1 0 1 100\n\
+         \n\
+         add s2c * 0 0 0.5 # 全ての表\n\
+         drop c2s 2\n",
+    )
+    .unwrap();
+    assert_eq!(rules.rules().len(), 3);
+    assert_eq!(rules.rules()[1].index, None);
+    assert_eq!(rules.rules()[2].action, mitm::Action::Drop);
+
+    let err = Rules::parse("drop c2s 1\nswap c2s 1\n").unwrap_err();
+    assert!(err.to_string().starts_with("Line 2"));
+    assert!(Rules::parse("drop c2s 0").is_err());
+    assert!(Rules::parse("set s2c 1 0 0").is_err());
+    assert!(Rules::parse("drop both 1").is_err());
+
+    let table = Codec::Json
+        .encode(&serde_json::json!({"data": [["1e0", "2e0"]]}))
+        .unwrap();
+    assert!(rules
+        .apply(Direction::ClientToServer, 3, Codec::Json, table.clone())
+        .unwrap()
+        .is_some());
+    assert!(rules
+        .apply(Direction::ClientToServer, 2, Codec::Json, table.clone())
+        .unwrap()
+        .is_none());
+    // 表の範囲外を書き換える規則はエラーになる
+    assert!(Rules::parse("set c2s * 1 0 0")
+        .unwrap()
+        .apply(Direction::ClientToServer, 1, Codec::Json, table)
+        .is_err());
+}
+
+#[test]
+fn mitm_relay_tests() {
+    let rules = Rules::parse("set c2s 1 0 1 100\nadd s2c * 0 0 0.5\ndrop c2s 2\n").unwrap();
+
+    let real_listener = TcpServer::builder().port(0).listen().unwrap();
+    let real_port = real_listener.local_addr().unwrap().port();
+    let mitm_listener = TcpServer::builder().port(0).listen().unwrap();
+    let mitm_port = mitm_listener.local_addr().unwrap().port();
+
+    let relay = thread::spawn(move || {
+        let client_side = mitm_listener.accept().unwrap().server;
+        let server_side = TcpClient::builder("127.0.0.1")
+            .port(real_port)
+            .build()
+            .unwrap();
+        mitm::run(client_side, server_side, rules)
+    });
+    let server = thread::spawn(move || {
+        let mut server = real_listener.accept().unwrap().server;
+        let table = server.receive_table().unwrap();
+        let message = server.receive().unwrap();
+        server.send_table(vec![vec![5.0]]).unwrap();
+        (table, message)
+    });
+
+    let mut client = TcpClient::builder("127.0.0.1")
+        .port(mitm_port)
+        .build()
+        .unwrap();
+    client
+        .send_table(vec![vec![1.0, 2.0], vec![3.0, 4.0]])
+        .unwrap();
+    client.send(b"dropped").unwrap();
+    client.send(b"hello").unwrap();
+    assert_eq!(client.receive_table().unwrap(), vec![vec![5.5]]);
+    drop(client);
+
+    let (table, message) = server.join().unwrap();
+    assert_eq!(table, vec![vec![1.0, 100.0], vec![3.0, 4.0]]);
+    assert_eq!(message, b"hello");
+    relay.join().unwrap().unwrap();
+}
+
+#[test]
+fn mitm_relay_shutdown_tests() {
+    // 表の範囲外を書き換える規則は適用せずに中継する
+    let rules = Rules::parse("set c2s * 5 5 100\n").unwrap();
+
+    let real_listener = TcpServer::builder().port(0).listen().unwrap();
+    let real_port = real_listener.local_addr().unwrap().port();
+    let mitm_listener = TcpServer::builder().port(0).listen().unwrap();
+    let mitm_port = mitm_listener.local_addr().unwrap().port();
+
+    let relay = thread::spawn(move || {
+        let client_side = mitm_listener.accept().unwrap().server;
+        let server_side = TcpClient::builder("127.0.0.1")
+            .port(real_port)
+            .build()
+            .unwrap();
+        mitm::run(client_side, server_side, rules)
+    });
+    let server = thread::spawn(move || {
+        let mut server = real_listener.accept().unwrap().server;
+        server.receive_table().unwrap()
+    });
+
+    let mut client = TcpClient::builder("127.0.0.1")
+        .port(mitm_port)
+        .build()
+        .unwrap();
+    client.send_table(vec![vec![1.0, 2.0]]).unwrap();
+    assert_eq!(server.join().unwrap(), vec![vec![1.0, 2.0]]);
+
+    // 予備校側が切断すれば、中学側が接続したままでも中継は終わる
+    relay.join().unwrap().unwrap();
+    // Lineフレーミングでは切断が空のメッセージとして見える
+    assert!(matches!(client.receive().as_deref(), Err(_) | Ok([])));
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls_tests() {
+    use crate::tls::{self, TlsIdentity};
+
+    let identity = TlsIdentity::self_signed(&["localhost"]).unwrap();
+    let pinned = identity.fingerprint();
+    assert_eq!(tls::fingerprint(identity.certificate_der()), pinned);
+
+    let listener = TcpServer::builder().port(0).listen().unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || listener.accept_tls(&identity).unwrap().server);
+    let tls_client = TcpClient::builder("localhost")
+        .port(port)
+        .build_tls(pinned)
+        .unwrap();
+    let tls_server = server.join().unwrap();
+
+    let tls_server = Arc::new(Mutex::new(tls_server));
+    let tls_client = Arc::new(Mutex::new(tls_client));
+    tests_base(Arc::clone(&tls_server), Arc::clone(&tls_client));
+
+    // 別の証明書を提示するサーバーにはハンドシェイクの時点で接続できない
+    let other = TlsIdentity::self_signed(&["localhost"]).unwrap();
+    let listener = TcpServer::builder().port(0).listen().unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || listener.accept_tls(&other).is_err());
+    assert!(TcpClient::builder("localhost")
+        .port(port)
+        .build_tls(pinned)
+        .is_err());
+    assert!(server.join().unwrap());
+}
+
+#[cfg(feature = "async")]
+mod async_tests {
+    use crate::async_comm::{AsyncChannelCommunicator, AsyncCommunicator, AsyncTcpCommunicator};
+    use crate::comm::{Communicator, Framing, TcpCommunicator};
+    use std::thread;
+
+    async fn ping_async<C1, C2>(c1: &mut C1, c2: &mut C2)
+    where
+        C1: AsyncCommunicator,
+        C2: AsyncCommunicator,
+    {
+        c1.send(b"ping").await.unwrap();
+        assert_eq!(c2.receive().await.unwrap(), b"ping");
+
+        c2.send(b"Hello,\nping!").await.unwrap();
+        assert_eq!(c1.receive().await.unwrap(), b"Hello,\nping!");
+
+        let matrix = vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]];
+        c1.send_table(matrix.clone()).await.unwrap();
+        assert_eq!(c2.receive_table().await.unwrap(), matrix);
+    }
+
+    #[tokio::test]
+    async fn async_channel_tests() {
+        let (s_tx, s_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (c_tx, c_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut server = AsyncChannelCommunicator::new(s_rx, c_tx);
+        let mut client = AsyncChannelCommunicator::new(c_rx, s_tx);
+
+        ping_async(&mut server, &mut client).await;
+        ping_async(&mut client, &mut server).await;
+    }
+
+    #[tokio::test]
+    async fn async_tcp_tests() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, accepted) = tokio::join!(
+            AsyncTcpCommunicator::connect(addr, Framing::LengthPrefixed),
+            listener.accept()
+        );
+        let mut client = client.unwrap();
+        let mut server = AsyncTcpCommunicator::new(accepted.unwrap().0, Framing::LengthPrefixed);
+
+        ping_async(&mut server, &mut client).await;
+        ping_async(&mut client, &mut server).await;
+    }
+
+    #[tokio::test]
+    async fn async_value_tests() {
+        let (s_tx, s_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (c_tx, c_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut server = AsyncChannelCommunicator::new(s_rx, c_tx);
+        let mut client = AsyncChannelCommunicator::new(c_rx, s_tx);
+
+        let params = super::sample_parameters();
+        server.send_value(&params).await.unwrap();
+        let received: super::Parameters = client.receive_value().await.unwrap();
+        assert_eq!(received, params);
+    }
+
+    // 同期版の`TcpCommunicator`とそのまま通信できる
+    #[tokio::test]
+    async fn async_sync_wire_compatibility_tests() {
+        for framing in [Framing::Line, Framing::EscapedLine, Framing::LengthPrefixed] {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let sync_peer = thread::spawn(move || {
+                let stream = std::net::TcpStream::connect(addr).unwrap();
+                let mut comm = TcpCommunicator::new(stream, framing).unwrap();
+
+                let data = comm.receive().unwrap();
+                comm.send(&data).unwrap();
+                let table = comm.receive_table().unwrap();
+                comm.send_table(table).unwrap();
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut comm = AsyncTcpCommunicator::new(stream, framing);
+
+            let message = b"Hello,\r\nping!".to_vec();
+            comm.send(&message).await.unwrap();
+            assert_eq!(comm.receive().await.unwrap(), message);
+
+            let matrix = vec![vec![1.5, -2.0], vec![3.25e10, 4.0]];
+            comm.send_table(matrix.clone()).await.unwrap();
+            assert_eq!(comm.receive_table().await.unwrap(), matrix);
+
+            sync_peer.join().unwrap();
+        }
+    }
+}
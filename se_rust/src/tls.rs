@@ -0,0 +1,319 @@
+use crate::client::Client;
+use crate::codec::Codec;
+use crate::comm::{normalize_timeout, Communicator, CommunicatorCore, Framing};
+use crate::server::Server;
+use anyhow::Result;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// rustlsによるTLS通信
+// 実験では自己署名証明書を使い、クライアント側は証明書のSHA-256フィンガープリントで相手を確認する
+
+pub type Fingerprint = [u8; 32];
+
+pub fn fingerprint(certificate_der: &[u8]) -> Fingerprint {
+    Sha256::digest(certificate_der).into()
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+// サーバーの証明書と秘密鍵
+pub struct TlsIdentity {
+    certificate: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl TlsIdentity {
+    // 動作確認用の自己署名証明書を作る。namesは"localhost"などのホスト名
+    pub fn self_signed(names: &[&str]) -> Result<Self> {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let certified = rcgen::generate_simple_self_signed(names)?;
+
+        Ok(Self {
+            certificate: certified.cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()),
+        })
+    }
+
+    // DER形式の証明書とPKCS#8形式の秘密鍵から作る
+    pub fn from_der(certificate: Vec<u8>, key: Vec<u8>) -> Self {
+        Self {
+            certificate: CertificateDer::from(certificate),
+            key: PrivatePkcs8KeyDer::from(key),
+        }
+    }
+
+    pub fn certificate_der(&self) -> &[u8] {
+        &self.certificate
+    }
+
+    pub fn key_der(&self) -> &[u8] {
+        self.key.secret_pkcs8_der()
+    }
+
+    // クライアントに事前に伝えておく値
+    pub fn fingerprint(&self) -> Fingerprint {
+        fingerprint(&self.certificate)
+    }
+
+    fn server_config(&self) -> Result<Arc<ServerConfig>> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![self.certificate.clone()],
+                PrivateKeyDer::Pkcs8(self.key.clone_key()),
+            )?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+// 証明書がフィンガープリントと一致するかだけを確認する
+// 署名の検証は通常通り行うので、秘密鍵を持たない相手は通らない
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: Fingerprint,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) != self.fingerprint {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn client_config(pinned: Fingerprint) -> Result<Arc<ClientConfig>> {
+    let provider = provider();
+    let verifier = PinnedCertVerifier {
+        fingerprint: pinned,
+        provider: Arc::clone(&provider),
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+enum TlsStream {
+    Server(StreamOwned<ServerConnection, TcpStream>),
+    Client(StreamOwned<ClientConnection, TcpStream>),
+}
+
+// 送信側と受信側で1つのTLSセッションを共有する
+#[derive(Clone)]
+pub struct SharedTlsStream(Arc<Mutex<TlsStream>>);
+
+impl SharedTlsStream {
+    fn socket<R>(&self, f: impl FnOnce(&TcpStream) -> R) -> R {
+        match &*self.0.lock().unwrap() {
+            TlsStream::Server(stream) => f(&stream.sock),
+            TlsStream::Client(stream) => f(&stream.sock),
+        }
+    }
+}
+
+impl Read for SharedTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut *self.0.lock().unwrap() {
+            TlsStream::Server(stream) => stream.read(buf),
+            TlsStream::Client(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for SharedTlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut *self.0.lock().unwrap() {
+            TlsStream::Server(stream) => stream.write(buf),
+            TlsStream::Client(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut *self.0.lock().unwrap() {
+            TlsStream::Server(stream) => stream.flush(),
+            TlsStream::Client(stream) => stream.flush(),
+        }
+    }
+}
+
+pub struct TlsCommunicator {
+    sender: SharedTlsStream,
+    receiver: BufReader<SharedTlsStream>,
+    framing: Framing,
+    codec: Codec,
+    partial: Vec<u8>,
+}
+
+impl TlsCommunicator {
+    // ハンドシェイクまで済ませるので、証明書が合わない場合はここでエラーになる
+    pub(crate) fn accept(
+        mut stream: TcpStream,
+        identity: &TlsIdentity,
+        framing: Framing,
+    ) -> Result<Self> {
+        let mut conn = ServerConnection::new(identity.server_config()?)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+
+        Ok(Self::new(
+            TlsStream::Server(StreamOwned::new(conn, stream)),
+            framing,
+        ))
+    }
+
+    pub(crate) fn connect(
+        mut stream: TcpStream,
+        server_name: &str,
+        pinned: Fingerprint,
+        framing: Framing,
+    ) -> Result<Self> {
+        let server_name = ServerName::try_from(server_name.to_string())?;
+        let mut conn = ClientConnection::new(client_config(pinned)?, server_name)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+
+        Ok(Self::new(
+            TlsStream::Client(StreamOwned::new(conn, stream)),
+            framing,
+        ))
+    }
+
+    fn new(stream: TlsStream, framing: Framing) -> Self {
+        let stream = SharedTlsStream(Arc::new(Mutex::new(stream)));
+
+        Self {
+            sender: stream.clone(),
+            receiver: BufReader::new(stream),
+            framing,
+            codec: Codec::default(),
+            partial: Vec::new(),
+        }
+    }
+}
+
+impl CommunicatorCore for TlsCommunicator {
+    type Sender = SharedTlsStream;
+    type Receiver = SharedTlsStream;
+
+    fn get_sender(&mut self) -> &mut Self::Sender {
+        &mut self.sender
+    }
+
+    fn get_receiver(&mut self) -> &mut BufReader<Self::Receiver> {
+        &mut self.receiver
+    }
+
+    fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    fn partial_frame(&mut self) -> Option<&mut Vec<u8>> {
+        Some(&mut self.partial)
+    }
+
+    fn read_timeout(&self) -> std::io::Result<Option<Duration>> {
+        self.sender.socket(|socket| socket.read_timeout())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.sender
+            .socket(|socket| socket.set_read_timeout(timeout))
+    }
+}
+
+impl Communicator for TlsCommunicator {
+    fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write(data).map_err(normalize_timeout)
+    }
+
+    fn receive(&mut self) -> std::io::Result<Vec<u8>> {
+        self.read().map_err(normalize_timeout)
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    fn set_codec(&mut self, codec: Codec) -> std::io::Result<()> {
+        codec.check_framing(self.framing)?;
+        self.codec = codec;
+        Ok(())
+    }
+}
+
+pub type TlsServer = Server<TlsCommunicator>;
+
+pub type TlsClient = Client<TlsCommunicator>;